@@ -0,0 +1 @@
+DROP TABLE bpp_transactions;
\ No newline at end of file
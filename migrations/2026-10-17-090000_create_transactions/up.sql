@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE bpp_transactions (
+    transaction_id BIGSERIAL PRIMARY KEY,
+    channel_id VARCHAR NOT NULL REFERENCES bpp_users(channel_id),
+    amount DOUBLE PRECISION NOT NULL,
+    reason VARCHAR NOT NULL,
+    actor VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX bpp_transactions_channel_id_created_at ON bpp_transactions(channel_id, created_at);
+
+-- Open the ledger of every existing user with their current balance
+INSERT INTO bpp_transactions (channel_id, amount, reason, actor, created_at)
+SELECT channel_id, money, 'opening', 'userservice', NOW() AT TIME ZONE 'utc'
+FROM bpp_users
+WHERE money <> 0;
@@ -0,0 +1,2 @@
+ALTER TABLE bpp_transactions ALTER COLUMN amount TYPE DOUBLE PRECISION USING amount::DOUBLE PRECISION;
+ALTER TABLE bpp_users ALTER COLUMN money TYPE DOUBLE PRECISION USING money::DOUBLE PRECISION;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Money is stored exactly in minor units (two decimal places), existing amounts are rounded to the nearest one.
+-- 18 digits keep every amount and the difference of two amounts within the int64 minor units of the API.
+ALTER TABLE bpp_users ALTER COLUMN money TYPE NUMERIC(18, 2) USING ROUND(money::NUMERIC, 2);
+ALTER TABLE bpp_transactions ALTER COLUMN amount TYPE NUMERIC(18, 2) USING ROUND(amount::NUMERIC, 2);
+
+-- Rounding every entry on its own can make a ledger drift from the rounded balance, book the difference
+INSERT INTO bpp_transactions (channel_id, amount, reason, actor, created_at)
+SELECT bpp_users.channel_id, bpp_users.money - COALESCE(SUM(bpp_transactions.amount), 0), 'adjustment', 'userservice', NOW() AT TIME ZONE 'utc'
+FROM bpp_users
+LEFT JOIN bpp_transactions ON bpp_transactions.channel_id = bpp_users.channel_id
+GROUP BY bpp_users.channel_id, bpp_users.money
+HAVING bpp_users.money <> COALESCE(SUM(bpp_transactions.amount), 0);
@@ -0,0 +1,2 @@
+DROP TABLE bpp_rank_history;
+ALTER TABLE bpp_users DROP COLUMN rank_id;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN rank_id INTEGER REFERENCES bpp_ranks(rank_id) ON DELETE SET NULL;
+
+-- Start with the rank every user currently has, the history only records changes from now on
+UPDATE bpp_users
+SET rank_id = (
+    SELECT bpp_ranks.rank_id
+    FROM bpp_ranks
+    WHERE bpp_ranks.hour_requirement_seconds <= bpp_users.hours_seconds
+    ORDER BY bpp_ranks.rank_sorting DESC
+    LIMIT 1
+);
+
+CREATE TABLE bpp_rank_history (
+    history_id BIGSERIAL PRIMARY KEY,
+    channel_id VARCHAR NOT NULL REFERENCES bpp_users(channel_id),
+    old_rank_id INTEGER REFERENCES bpp_ranks(rank_id) ON DELETE SET NULL,
+    old_rank_name VARCHAR NOT NULL,
+    new_rank_id INTEGER REFERENCES bpp_ranks(rank_id) ON DELETE SET NULL,
+    new_rank_name VARCHAR NOT NULL,
+    changed_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX bpp_rank_history_channel_id_changed_at ON bpp_rank_history(channel_id, changed_at);
@@ -0,0 +1,2 @@
+DROP INDEX bpp_users_rank_id;
+DROP INDEX bpp_users_display_name_trgm;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- Creating the extension needs a role which may create it, like the database owner on PostgreSQL 13 or later.
+-- Otherwise it has to be created beforehand by a superuser.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- Serves both the substring and the fuzzy search on display names
+CREATE INDEX bpp_users_display_name_trgm ON bpp_users USING GIN (display_name gin_trgm_ops);
+CREATE INDEX bpp_users_rank_id ON bpp_users(rank_id);
@@ -0,0 +1,2 @@
+DROP INDEX bpp_users_money;
+DROP INDEX bpp_users_hours_seconds;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+CREATE INDEX bpp_users_hours_seconds ON bpp_users(hours_seconds DESC, channel_id);
+CREATE INDEX bpp_users_money ON bpp_users(money DESC, channel_id);
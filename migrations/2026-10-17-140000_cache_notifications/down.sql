@@ -0,0 +1,6 @@
+DROP TRIGGER bpp_users_permissions_notify_cache ON bpp_users_permissions;
+DROP TRIGGER bpp_ranks_notify_cache ON bpp_ranks;
+DROP TRIGGER bpp_groups_users_notify_cache ON bpp_groups_users;
+DROP TRIGGER bpp_groups_permissions_notify_cache ON bpp_groups_permissions;
+DROP TRIGGER bpp_groups_notify_cache ON bpp_groups;
+DROP FUNCTION bpp_notify_cache();
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE FUNCTION bpp_notify_cache() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('bpp_cache', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bpp_groups_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_groups
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_groups_permissions_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_groups_permissions
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_groups_users_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_groups_users
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_ranks_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_ranks
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_users_permissions_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_users_permissions
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
@@ -0,0 +1,2 @@
+DROP TABLE bpp_sessions;
+DROP TABLE bpp_broadcasts;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+CREATE TABLE bpp_broadcasts (
+    broadcast_id SERIAL PRIMARY KEY,
+    title VARCHAR NOT NULL,
+    video_id VARCHAR NOT NULL,
+    started_at TIMESTAMP NOT NULL,
+    ended_at TIMESTAMP
+);
+
+-- Only one broadcast can be running at a time
+CREATE UNIQUE INDEX bpp_broadcasts_running ON bpp_broadcasts((ended_at IS NULL)) WHERE ended_at IS NULL;
+
+CREATE TABLE bpp_sessions (
+    session_id BIGSERIAL PRIMARY KEY,
+    channel_id VARCHAR NOT NULL REFERENCES bpp_users(channel_id),
+    broadcast_id INTEGER REFERENCES bpp_broadcasts(broadcast_id),
+    started_at TIMESTAMP NOT NULL,
+    ended_at TIMESTAMP NOT NULL,
+    message_count INTEGER NOT NULL,
+    duration_seconds BIGINT NOT NULL
+);
+
+CREATE INDEX bpp_sessions_channel_id_ended_at ON bpp_sessions(channel_id, ended_at);
+CREATE INDEX bpp_sessions_broadcast_id_channel_id ON bpp_sessions(broadcast_id, channel_id);
@@ -0,0 +1 @@
+ALTER TABLE bpp_broadcasts DROP COLUMN state;
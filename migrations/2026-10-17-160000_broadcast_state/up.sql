@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Broadcasts recorded so far were all started as live broadcasts
+ALTER TABLE bpp_broadcasts ADD COLUMN state VARCHAR NOT NULL DEFAULT 'live';
+ALTER TABLE bpp_broadcasts ALTER COLUMN state DROP DEFAULT;
@@ -0,0 +1,2 @@
+DROP INDEX bpp_users_deleted_at;
+ALTER TABLE bpp_users DROP COLUMN deleted_at;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN deleted_at TIMESTAMP NULL;
+-- Only deleted users are looked up by their deletion time, for listing and purging them
+CREATE INDEX bpp_users_deleted_at ON bpp_users(deleted_at) WHERE deleted_at IS NOT NULL;
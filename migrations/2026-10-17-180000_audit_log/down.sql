@@ -0,0 +1 @@
+DROP TABLE bpp_audit_log;
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE bpp_audit_log (
+    audit_id BIGSERIAL PRIMARY KEY,
+    actor VARCHAR NOT NULL,
+    rpc VARCHAR NOT NULL,
+    -- What the change was made to, e.g. 'user' and a channel id or 'group' and a group id
+    target_type VARCHAR NOT NULL,
+    target_id VARCHAR NOT NULL,
+    before_value JSONB NULL,
+    after_value JSONB NULL,
+    created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX bpp_audit_log_created_at ON bpp_audit_log(created_at);
+CREATE INDEX bpp_audit_log_actor ON bpp_audit_log(actor, created_at);
+CREATE INDEX bpp_audit_log_target ON bpp_audit_log(target_type, target_id, created_at);
@@ -0,0 +1,3 @@
+DELETE FROM bpp_transactions WHERE channel_id NOT IN (SELECT channel_id FROM bpp_users);
+ALTER TABLE bpp_transactions ADD CONSTRAINT bpp_transactions_channel_id_fkey
+    FOREIGN KEY (channel_id) REFERENCES bpp_users(channel_id);
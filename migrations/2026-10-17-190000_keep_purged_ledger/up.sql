@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- The ledger is append-only, so it keeps the entries of purged users under a pseudonym
+ALTER TABLE bpp_transactions DROP CONSTRAINT bpp_transactions_channel_id_fkey;
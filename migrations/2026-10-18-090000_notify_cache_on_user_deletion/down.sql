@@ -0,0 +1 @@
+DROP TRIGGER bpp_users_deleted_at_notify_cache ON bpp_users;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- The cache leaves out the memberships and permissions of deleted users, so deleting or restoring one changes it.
+-- Databases migrated while the trigger was part of the soft_delete_users migration have it already.
+DROP TRIGGER IF EXISTS bpp_users_deleted_at_notify_cache ON bpp_users;
+CREATE TRIGGER bpp_users_deleted_at_notify_cache AFTER UPDATE OF deleted_at ON bpp_users
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
@@ -0,0 +1,6 @@
+CREATE TRIGGER bpp_users_deleted_at_notify_cache AFTER UPDATE OF deleted_at ON bpp_users
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_users_permissions_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_users_permissions
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
+CREATE TRIGGER bpp_groups_users_notify_cache AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON bpp_groups_users
+    FOR EACH STATEMENT EXECUTE FUNCTION bpp_notify_cache();
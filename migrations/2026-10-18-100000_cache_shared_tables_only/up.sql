@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Memberships and user permissions are loaded per request instead of cached, so changes to them needn't be announced
+DROP TRIGGER bpp_groups_users_notify_cache ON bpp_groups_users;
+DROP TRIGGER bpp_users_permissions_notify_cache ON bpp_users_permissions;
+DROP TRIGGER bpp_users_deleted_at_notify_cache ON bpp_users;
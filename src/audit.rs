@@ -0,0 +1,223 @@
+use bigdecimal::BigDecimal;
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Text};
+use serde_json::{json, Value};
+
+use crate::models::{naive_to_rfc3339, naive_to_timestamp, AuditEntry, Broadcast, Group, InsertAuditEntry, Rank, User};
+use crate::money;
+use crate::paging::Page;
+use crate::schema::bpp_audit_log;
+use crate::userservice;
+
+/// The target type of changes made to users
+const USER_TARGET_TYPE: &str = "user";
+
+/// What an administrative change was made to
+pub enum Target {
+    User(String),
+    Group(i32),
+    Rank(i32),
+    Broadcast(i32),
+}
+
+impl Target {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Target::User(_) => USER_TARGET_TYPE,
+            Target::Group(_) => "group",
+            Target::Rank(_) => "rank",
+            Target::Broadcast(_) => "broadcast",
+        }
+    }
+
+    pub fn id(&self) -> String {
+        match self {
+            Target::User(channel_id) => channel_id.clone(),
+            Target::Group(group_id) => group_id.to_string(),
+            Target::Rank(rank_id) => rank_id.to_string(),
+            Target::Broadcast(broadcast_id) => broadcast_id.to_string(),
+        }
+    }
+}
+
+/// Records a change made through the API, `before` isn't set for created targets and `after` isn't set for removed
+/// ones.
+///
+/// Callers are expected to record the change inside the transaction which makes it, so every change is recorded.
+pub fn record(
+    actor: &str,
+    rpc: &str,
+    target: Target,
+    before: Option<Value>,
+    after: Option<Value>,
+    conn: &PgConnection,
+) -> QueryResult<()> {
+    let entry = InsertAuditEntry {
+        actor: actor.to_string(),
+        rpc: rpc.to_string(),
+        target_type: target.kind().to_string(),
+        target_id: target.id(),
+        before_value: before,
+        after_value: after,
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(bpp_audit_log::table).values(&entry).execute(conn)?;
+    Ok(())
+}
+
+pub fn user_value(user: &User) -> Value {
+    json!({
+        "channel_id": user.channel_id,
+        "display_name": user.display_name,
+        "hours_seconds": user.hours_seconds,
+        "money": money::to_minor_units(&user.money),
+        "first_seen_at": naive_to_rfc3339(&user.first_seen_at),
+        "last_seen_at": naive_to_rfc3339(&user.last_seen_at),
+        "deleted_at": user.deleted_at.as_ref().map(naive_to_rfc3339),
+    })
+}
+
+pub fn money_value(money: &BigDecimal) -> Value {
+    json!({ "money": money::to_minor_units(money) })
+}
+
+pub fn hours_value(hours_seconds: i64) -> Value {
+    json!({ "hours_seconds": hours_seconds })
+}
+
+pub fn group_value(group: &Group) -> Value {
+    json!({
+        "group_id": group.group_id,
+        "group_name": group.group_name,
+        "bonus_payout": group.bonus_payout,
+        "group_sorting": group.group_sorting,
+    })
+}
+
+pub fn rank_value(rank: &Rank) -> Value {
+    json!({
+        "rank_id": rank.rank_id,
+        "rank_name": rank.rank_name,
+        "rank_sorting": rank.rank_sorting,
+        "hour_requirement_seconds": rank.hour_requirement_seconds,
+    })
+}
+
+pub fn permission_value(permission: &str, granted: bool) -> Value {
+    json!({ "permission": permission, "granted": granted })
+}
+
+pub fn membership_value(group_id: i32) -> Value {
+    json!({ "group_id": group_id })
+}
+
+pub fn broadcast_value(broadcast: &Broadcast) -> Value {
+    json!({
+        "broadcast_id": broadcast.broadcast_id,
+        "title": broadcast.title,
+        "video_id": broadcast.video_id,
+        "state": broadcast.state,
+        "started_at": naive_to_rfc3339(&broadcast.started_at),
+        "ended_at": broadcast.ended_at.as_ref().map(naive_to_rfc3339),
+    })
+}
+
+/// Loads the entries a user made or which were made to them, oldest first
+pub fn entries_of_user(channel_id: &str, conn: &PgConnection) -> QueryResult<Vec<AuditEntry>> {
+    bpp_audit_log::table
+        .filter(
+            bpp_audit_log::actor.eq(channel_id).or(bpp_audit_log::target_type
+                .eq(USER_TARGET_TYPE)
+                .and(bpp_audit_log::target_id.eq(channel_id))),
+        )
+        .order(bpp_audit_log::audit_id.asc())
+        .load::<AuditEntry>(conn)
+}
+
+/// Replaces a channel id with a pseudonym in all entries and drops the display name and the first and last seen times
+/// from the entries of the user
+pub fn pseudonymise_user(channel_id: &str, pseudonym: &str, conn: &PgConnection) -> QueryResult<()> {
+    diesel::update(bpp_audit_log::table.filter(bpp_audit_log::actor.eq(channel_id)))
+        .set(bpp_audit_log::actor.eq(pseudonym))
+        .execute(conn)?;
+    diesel::update(
+        bpp_audit_log::table
+            .filter(bpp_audit_log::target_type.eq(USER_TARGET_TYPE))
+            .filter(bpp_audit_log::target_id.eq(channel_id)),
+    )
+    .set(bpp_audit_log::target_id.eq(pseudonym))
+    .execute(conn)?;
+    // Only the user values of the user's own entries hold their channel id, next to their display name and times
+    diesel::sql_query(
+        "UPDATE bpp_audit_log
+        SET before_value = jsonb_set(before_value - $3::TEXT[], '{channel_id}', to_jsonb($2::TEXT), false),
+            after_value = jsonb_set(after_value - $3::TEXT[], '{channel_id}', to_jsonb($2::TEXT), false)
+        WHERE target_type = $1 AND target_id = $2",
+    )
+    .bind::<Text, _>(USER_TARGET_TYPE)
+    .bind::<Text, _>(pseudonym)
+    .bind::<Array<Text>, _>(&["display_name", "first_seen_at", "last_seen_at"][..])
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Which entries to load, empty strings and unset times match every entry
+pub struct AuditFilter {
+    pub actor: String,
+    pub rpc: String,
+    pub target_type: String,
+    pub target_id: String,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+fn filtered_entries(filter: &AuditFilter) -> bpp_audit_log::BoxedQuery<'static, Pg> {
+    let mut query = bpp_audit_log::table.into_boxed();
+    if !filter.actor.is_empty() {
+        query = query.filter(bpp_audit_log::actor.eq(filter.actor.clone()));
+    }
+    if !filter.rpc.is_empty() {
+        query = query.filter(bpp_audit_log::rpc.eq(filter.rpc.clone()));
+    }
+    if !filter.target_type.is_empty() {
+        query = query.filter(bpp_audit_log::target_type.eq(filter.target_type.clone()));
+    }
+    if !filter.target_id.is_empty() {
+        query = query.filter(bpp_audit_log::target_id.eq(filter.target_id.clone()));
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(bpp_audit_log::created_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(bpp_audit_log::created_at.lt(until));
+    }
+    query
+}
+
+/// Loads one page of audit entries, newest first, together with the total number of matching entries
+pub fn get_entries(filter: &AuditFilter, page: &Page, conn: &PgConnection) -> QueryResult<(i64, Vec<AuditEntry>)> {
+    let total = filtered_entries(filter).count().get_result(conn)?;
+    let entries = filtered_entries(filter)
+        .order((bpp_audit_log::created_at.desc(), bpp_audit_log::audit_id.desc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<AuditEntry>(conn)?;
+    Ok((total, entries))
+}
+
+impl From<AuditEntry> for userservice::AuditEntry {
+    fn from(entry: AuditEntry) -> userservice::AuditEntry {
+        userservice::AuditEntry {
+            audit_id: entry.audit_id,
+            actor: entry.actor,
+            rpc: entry.rpc,
+            target_type: entry.target_type,
+            target_id: entry.target_id,
+            before: entry.before_value.map(|value| value.to_string()).unwrap_or_default(),
+            after: entry.after_value.map(|value| value.to_string()).unwrap_or_default(),
+            created_at: Some(naive_to_timestamp(&entry.created_at)),
+        }
+    }
+}
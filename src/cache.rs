@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use diesel::prelude::*;
+use futures_util::{stream, StreamExt};
+use log::{debug, error, info};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::models::{Group, GroupPermission, GroupUser, Rank, UserPermission};
+use crate::schema::{bpp_groups, bpp_groups_permissions, bpp_groups_users, bpp_ranks, bpp_users, bpp_users_permissions};
+
+/// The channel the triggers of the cached tables notify on
+const CACHE_CHANNEL: &str = "bpp_cache";
+/// How long to wait before listening again after the notification connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Groups, ranks and the permissions of groups as they were at one point in time
+pub struct Snapshot {
+    groups: HashMap<i32, Group>,
+    group_permissions: HashMap<i32, Vec<GroupPermission>>,
+    /// Sorted by descending rank sorting
+    ranks: Vec<Rank>,
+}
+
+impl Snapshot {
+    fn load(conn: &PgConnection) -> QueryResult<Snapshot> {
+        conn.build_transaction().repeatable_read().read_only().run(|| {
+            let groups = bpp_groups::table.load::<Group>(conn)?;
+            let group_permissions = bpp_groups_permissions::table.load::<GroupPermission>(conn)?;
+            let ranks = bpp_ranks::table
+                .order(bpp_ranks::rank_sorting.desc())
+                .load::<Rank>(conn)?;
+
+            let mut snapshot = Snapshot {
+                groups: groups.into_iter().map(|group| (group.group_id, group)).collect(),
+                group_permissions: HashMap::new(),
+                ranks,
+            };
+            for permission in group_permissions {
+                snapshot.group_permissions.entry(permission.group_id).or_default().push(permission);
+            }
+            Ok(snapshot)
+        })
+    }
+
+    /// Returns the groups of a user, whose memberships have to be loaded into `grants`
+    pub fn groups_for_user(&self, grants: &UserGrants, channel_id: &str) -> Vec<&Group> {
+        grants
+            .memberships
+            .get(channel_id)
+            .map(|group_ids| group_ids.iter().filter_map(|group_id| self.groups.get(group_id)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn permissions_for_group(&self, group_id: i32) -> &[GroupPermission] {
+        self.group_permissions.get(&group_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Returns the rank a user with these hours has, following the same rules as `User::get_active_rank`
+    pub fn active_rank(&self, hours_seconds: i64) -> Option<&Rank> {
+        self.ranks.iter().find(|rank| rank.hour_requirement_seconds <= hours_seconds)
+    }
+}
+
+/// The group memberships and permissions of some users.
+///
+/// There is a row per user in these tables, so unlike the groups they aren't cached but loaded for the users a request
+/// is about.
+pub struct UserGrants {
+    memberships: HashMap<String, Vec<i32>>,
+    user_permissions: HashMap<String, Vec<UserPermission>>,
+}
+
+impl UserGrants {
+    /// Loads the memberships and permissions of the users in two queries
+    pub fn load(channel_ids: &[&str], conn: &PgConnection) -> QueryResult<UserGrants> {
+        let mut grants = UserGrants {
+            memberships: HashMap::new(),
+            user_permissions: HashMap::new(),
+        };
+        if channel_ids.is_empty() {
+            return Ok(grants);
+        }
+        // Deleted users keep their memberships and permissions for a restore, but mustn't be granted anything
+        let memberships = bpp_groups_users::table
+            .inner_join(bpp_users::table)
+            .filter(bpp_groups_users::channel_id.eq_any(channel_ids))
+            .filter(bpp_users::deleted_at.is_null())
+            .select(bpp_groups_users::all_columns)
+            .load::<GroupUser>(conn)?;
+        let user_permissions = bpp_users_permissions::table
+            .inner_join(bpp_users::table)
+            .filter(bpp_users_permissions::channel_id.eq_any(channel_ids))
+            .filter(bpp_users::deleted_at.is_null())
+            .select(bpp_users_permissions::all_columns)
+            .load::<UserPermission>(conn)?;
+
+        for membership in memberships {
+            grants.memberships.entry(membership.channel_id).or_default().push(membership.group_id);
+        }
+        for permission in user_permissions {
+            grants.user_permissions.entry(permission.channel_id.clone()).or_default().push(permission);
+        }
+        Ok(grants)
+    }
+
+    pub fn permissions_for_user(&self, channel_id: &str) -> &[UserPermission] {
+        self.user_permissions.get(channel_id).map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+struct CacheState {
+    /// Increased by every invalidation, so a snapshot loaded across an invalidation isn't kept
+    generation: u64,
+    snapshot: Option<Arc<Snapshot>>,
+}
+
+/// A process-wide cache of the rarely changing groups and ranks needed for payouts and permission checks.
+///
+/// The cache is loaded lazily and has to be invalidated after every write to the cached tables, either by the writer
+/// itself or through Postgres notifications, see [`Cache::listen_for_changes`].
+#[derive(Clone)]
+pub struct Cache {
+    state: Arc<RwLock<CacheState>>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            state: Arc::new(RwLock::new(CacheState {
+                generation: 0,
+                snapshot: None,
+            })),
+        }
+    }
+
+    /// Returns the current snapshot, loading it from the database if the cache was invalidated
+    pub fn get(&self, conn: &PgConnection) -> QueryResult<Arc<Snapshot>> {
+        let generation = {
+            let state = self.state.read().unwrap();
+            if let Some(snapshot) = &state.snapshot {
+                return Ok(snapshot.clone());
+            }
+            state.generation
+        };
+
+        debug!("Loading groups, ranks and permissions into the cache");
+        let snapshot = Arc::new(Snapshot::load(conn)?);
+        let mut state = self.state.write().unwrap();
+        if state.generation == generation {
+            state.snapshot = Some(snapshot.clone());
+        }
+        Ok(snapshot)
+    }
+
+    pub fn invalidate(&self) {
+        let mut state = self.state.write().unwrap();
+        state.generation += 1;
+        state.snapshot = None;
+    }
+
+    /// Invalidates the cache whenever the triggers of the cached tables send a notification, which also catches
+    /// changes made by other instances or directly in the database. Runs until the process ends.
+    pub async fn listen_for_changes(self, database_url: String) {
+        loop {
+            if let Err(e) = self.listen(&database_url).await {
+                error!("Listening for cache notifications failed: {}", e);
+            }
+            // Notifications may have been missed while not listening
+            self.invalidate();
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn listen(&self, database_url: &str) -> Result<(), tokio_postgres::Error> {
+        let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        let listen_statement = format!("LISTEN {}", CACHE_CHANNEL);
+        let listen = client.batch_execute(&listen_statement);
+        tokio::pin!(listen);
+
+        // The connection has to be driven while the LISTEN is sent
+        loop {
+            tokio::select! {
+                result = &mut listen => {
+                    result?;
+                    break;
+                }
+                message = messages.next() => match message {
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(_)) => {}
+                    None => return Ok(()),
+                }
+            }
+        }
+        info!("Listening for cache notifications");
+        self.invalidate();
+
+        while let Some(message) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message? {
+                debug!("{} changed, invalidating the cache", notification.payload());
+                self.invalidate();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new()
+    }
+}
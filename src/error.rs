@@ -0,0 +1,76 @@
+use config::ConfigError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::QueryResult;
+use log::error;
+use tonic::Status;
+
+/// Why a request failed, every kind maps to one gRPC status code
+#[derive(Debug)]
+pub enum ServiceError {
+    /// No database connection could be taken from the pool in time
+    Unavailable(String),
+    NotFound(String),
+    /// The request conflicts with the stored data, like a duplicate key or a row which is still referenced
+    FailedPrecondition(String),
+    InvalidArgument(String),
+    /// Any other database error, the details are logged instead of being sent to the client
+    Database(DieselError),
+    /// The settings couldn't be loaded, the details are logged instead of being sent to the client
+    Settings(ConfigError),
+}
+
+impl ServiceError {
+    /// Replaces the generic not found error of a query with one saying what wasn't found
+    pub fn not_found<T>(result: QueryResult<T>, message: &str) -> Result<T, ServiceError> {
+        result.map_err(|e| match e {
+            DieselError::NotFound => ServiceError::NotFound(message.to_string()),
+            e => e.into(),
+        })
+    }
+}
+
+impl From<r2d2::Error> for ServiceError {
+    fn from(e: r2d2::Error) -> ServiceError {
+        error!("Failed to get a database connection: {}", e);
+        ServiceError::Unavailable("No database connection available".to_string())
+    }
+}
+
+impl From<DieselError> for ServiceError {
+    fn from(e: DieselError) -> ServiceError {
+        match e {
+            DieselError::NotFound => ServiceError::NotFound("Not found".to_string()),
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+            | DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, info) => {
+                // The details name the offending key, e.g. "Key (group_id)=(1) is still referenced from ..."
+                ServiceError::FailedPrecondition(info.details().unwrap_or_else(|| info.message()).to_string())
+            }
+            e => ServiceError::Database(e),
+        }
+    }
+}
+
+impl From<ConfigError> for ServiceError {
+    fn from(e: ConfigError) -> ServiceError {
+        ServiceError::Settings(e)
+    }
+}
+
+impl From<ServiceError> for Status {
+    fn from(e: ServiceError) -> Status {
+        match e {
+            ServiceError::Unavailable(message) => Status::unavailable(message),
+            ServiceError::NotFound(message) => Status::not_found(message),
+            ServiceError::FailedPrecondition(message) => Status::failed_precondition(message),
+            ServiceError::InvalidArgument(message) => Status::invalid_argument(message),
+            ServiceError::Database(e) => {
+                error!("{}", e);
+                Status::internal("Database error")
+            }
+            ServiceError::Settings(e) => {
+                error!("Failed to load settings: {}", e);
+                Status::internal("Failed to load settings")
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use bigdecimal::BigDecimal;
+use chrono::Utc;
+use log::warn;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+use crate::ledger::TransactionReason;
+use crate::models::{naive_to_timestamp, RankHistory};
+use crate::money;
+use crate::userservice::bpp_transaction::Reason;
+use crate::userservice::user_event::Payload;
+use crate::userservice::{
+    BppUser, EventSubscription, EventType, GroupMembershipChanged, MoneyAdjusted, PermissionChanged, RankChanged,
+    UserCreated, UserEvent, UserUpdated,
+};
+
+/// How many events a subscriber may fall behind before it starts missing events
+const EVENT_BUFFER_SIZE: usize = 1024;
+/// How many matching events are queued for a single subscriber stream
+const SUBSCRIBER_BUFFER_SIZE: usize = 64;
+
+/// Distributes change events to all subscribers.
+///
+/// Events are only published after the change they describe has been committed, events without any subscriber are
+/// dropped.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<UserEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        EventBus { sender }
+    }
+
+    /// Whether anyone would receive a published event right now
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, event: UserEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn publish_all(&self, events: Vec<UserEvent>) {
+        for event in events {
+            self.publish(event);
+        }
+    }
+
+    /// Returns a stream of all future events matching the subscription, which ends once the client disconnects
+    pub fn subscribe(&self, subscription: EventSubscription) -> ReceiverStream<Result<UserEvent, Status>> {
+        let mut receiver = self.sender.subscribe();
+        let (sender, stream) = mpsc::channel(SUBSCRIBER_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        if matches(&subscription, &event) && sender.send(Ok(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("An event subscriber fell behind and missed {} events", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        ReceiverStream::new(stream)
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+/// Checks if an event is wanted by a subscription, empty filters match everything.
+///
+/// Events which don't concern a single user, like permission changes of a group, have no channel id and are
+/// therefore only delivered to subscriptions without a channel id filter.
+pub fn matches(subscription: &EventSubscription, event: &UserEvent) -> bool {
+    (subscription.event_types.is_empty() || subscription.event_types.contains(&event.event_type))
+        && (subscription.channel_ids.is_empty() || subscription.channel_ids.contains(&event.channel_id))
+}
+
+fn event(event_type: EventType, channel_id: &str, payload: Payload) -> UserEvent {
+    UserEvent {
+        event_type: event_type as i32,
+        channel_id: channel_id.to_string(),
+        occurred_at: Some(naive_to_timestamp(&Utc::now().naive_utc())),
+        payload: Some(payload),
+    }
+}
+
+pub fn user_created(user: BppUser) -> UserEvent {
+    let channel_id = user.channel_id.clone();
+    event(EventType::UserCreated, &channel_id, Payload::UserCreated(UserCreated { user: Some(user) }))
+}
+
+pub fn user_updated(user: BppUser, changed_fields: Vec<String>) -> UserEvent {
+    let channel_id = user.channel_id.clone();
+    event(
+        EventType::UserUpdated,
+        &channel_id,
+        Payload::UserUpdated(UserUpdated {
+            user: Some(user),
+            changed_fields,
+        }),
+    )
+}
+
+pub fn rank_changed(change: RankHistory) -> UserEvent {
+    event(
+        EventType::RankChanged,
+        &change.channel_id,
+        Payload::RankChanged(RankChanged {
+            old_rank: change.old_rank_name,
+            new_rank: change.new_rank_name,
+            old_rank_id: change.old_rank_id.unwrap_or(0),
+            new_rank_id: change.new_rank_id.unwrap_or(0),
+        }),
+    )
+}
+
+pub fn group_membership_changed(channel_id: &str, group_id: i32, added: bool) -> UserEvent {
+    event(
+        EventType::GroupMembershipChanged,
+        channel_id,
+        Payload::GroupMembershipChanged(GroupMembershipChanged { group_id, added }),
+    )
+}
+
+/// A permission of a user, or of a group if the channel id is empty, was granted or revoked
+pub fn permission_changed(channel_id: &str, group_id: i32, permission: String, granted: bool) -> UserEvent {
+    event(
+        EventType::PermissionChanged,
+        channel_id,
+        Payload::PermissionChanged(PermissionChanged {
+            group_id,
+            permission,
+            granted,
+        }),
+    )
+}
+
+pub fn money_adjusted(channel_id: &str, delta: &BigDecimal, balance: &BigDecimal, reason: TransactionReason) -> UserEvent {
+    let reason: Reason = reason.into();
+    event(
+        EventType::MoneyAdjusted,
+        channel_id,
+        Payload::MoneyAdjusted(MoneyAdjusted {
+            delta: money::to_minor_units(delta),
+            balance: money::to_minor_units(balance),
+            reason: reason as i32,
+        }),
+    )
+}
@@ -0,0 +1,357 @@
+use diesel::dsl::{not, sql};
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Int4, Text};
+
+use crate::error::ServiceError;
+use crate::models::timestamp_to_naive;
+use crate::schema::{bpp_groups_users, bpp_users};
+use crate::userservice::bpp_user_filter::Filter;
+use crate::userservice::bpp_user_filter_expression::{Node, Operands};
+use crate::userservice::bpp_user_filters::SortingFields;
+use crate::userservice::sort_key::Field;
+use crate::userservice::{
+    BppUserFilter, BppUserFilterExpression, BppUserFilters, ComparisonOperator, Int64Comparison, TimestampComparison,
+};
+
+/// A condition on a user, which can be used to filter user queries
+pub type UserPredicate = Box<dyn BoxableExpression<bpp_users::table, Pg, SqlType = Bool>>;
+
+/// How deeply filter expressions may be nested
+const MAX_EXPRESSION_DEPTH: usize = 32;
+
+// Matches strings which are similar according to pg_trgm, the similarity threshold is configured in the database
+diesel_infix_operator!(SimilarTo, " % ", backend: Pg);
+
+/// Compares a column with the value of a comparison, `$upper` is only evaluated for `BETWEEN`
+macro_rules! compare_column {
+    ($column:expr, $operator:expr, $value:expr, $upper:expr) => {
+        match $operator {
+            ComparisonOperator::Eq => Box::new($column.eq($value)) as UserPredicate,
+            ComparisonOperator::Lt => Box::new($column.lt($value)),
+            ComparisonOperator::Le => Box::new($column.le($value)),
+            ComparisonOperator::Gt => Box::new($column.gt($value)),
+            ComparisonOperator::Ge => Box::new($column.ge($value)),
+            ComparisonOperator::Between => Box::new($column.between($value, $upper)),
+        }
+    };
+}
+
+fn comparison_operator(operator: i32) -> Result<ComparisonOperator, ServiceError> {
+    ComparisonOperator::from_i32(operator).ok_or_else(|| ServiceError::InvalidArgument("Unknown comparison operator".to_string()))
+}
+
+fn check_int64_comparison(comparison: &Int64Comparison) -> Result<ComparisonOperator, ServiceError> {
+    let operator = comparison_operator(comparison.operator)?;
+    if operator == ComparisonOperator::Between && comparison.upper < comparison.value {
+        return Err(ServiceError::InvalidArgument("Upper bound of a range must not be below its lower bound".to_string()));
+    }
+    Ok(operator)
+}
+
+fn check_timestamp_comparison(
+    comparison: &TimestampComparison,
+) -> Result<(ComparisonOperator, chrono::NaiveDateTime, chrono::NaiveDateTime), ServiceError> {
+    let operator = comparison_operator(comparison.operator)?;
+    let value = match &comparison.value {
+        Some(value) => timestamp_to_naive(value)?,
+        None => return Err(ServiceError::InvalidArgument("Timestamp comparison without a value".to_string())),
+    };
+    let upper = match (&comparison.upper, operator) {
+        (Some(upper), _) => timestamp_to_naive(upper)?,
+        (None, ComparisonOperator::Between) => {
+            return Err(ServiceError::InvalidArgument("Timestamp range without an upper bound".to_string()));
+        }
+        (None, _) => value,
+    };
+    if upper < value {
+        return Err(ServiceError::InvalidArgument("Upper bound of a range must not be below its lower bound".to_string()));
+    }
+    Ok((operator, value, upper))
+}
+
+/// Escapes the wildcards of LIKE, so the text only matches itself
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Translates a single filter sent by a client into a predicate
+pub fn filter_predicate(filter: &BppUserFilter) -> Result<UserPredicate, ServiceError> {
+    use crate::schema::bpp_users::dsl::*;
+    let inner_filter = match &filter.filter {
+        Some(inner_filter) => inner_filter,
+        None => return Err(ServiceError::InvalidArgument("Empty user filter".to_string())),
+    };
+
+    let predicate: UserPredicate = match inner_filter {
+        Filter::ChannelId(filter_channel_id) => Box::new(channel_id.eq(filter_channel_id.clone())),
+        Filter::Name(filter_name) => Box::new(display_name.eq(filter_name.clone())),
+        Filter::Hours(filter_hours) => Box::new(hours_seconds.eq(*filter_hours)),
+        Filter::Money(filter_money) => match crate::money::units_float_to_minor_units(*filter_money) {
+            Some(minor_units) => Box::new(money.eq(crate::money::from_minor_units(minor_units))),
+            None => return Err(ServiceError::InvalidArgument("Money exceeds the supported range".to_string())),
+        },
+        Filter::MoneyMinorUnits(minor_units) => Box::new(money.eq(crate::money::from_minor_units(*minor_units))),
+        Filter::HoursComparison(comparison) => {
+            let operator = check_int64_comparison(comparison)?;
+            compare_column!(hours_seconds, operator, comparison.value, comparison.upper)
+        }
+        Filter::MoneyComparison(comparison) => {
+            let operator = check_int64_comparison(comparison)?;
+            compare_column!(
+                money,
+                operator,
+                crate::money::from_minor_units(comparison.value),
+                crate::money::from_minor_units(comparison.upper)
+            )
+        }
+        Filter::FirstSeenAt(comparison) => {
+            let (operator, value, upper) = check_timestamp_comparison(comparison)?;
+            compare_column!(first_seen_at, operator, value, upper)
+        }
+        Filter::LastSeenAt(comparison) => {
+            let (operator, value, upper) = check_timestamp_comparison(comparison)?;
+            compare_column!(last_seen_at, operator, value, upper)
+        }
+        Filter::NameContains(text) => Box::new(display_name.ilike(format!("%{}%", escape_like(text)))),
+        Filter::NameSimilarTo(text) => {
+            if text.trim().is_empty() {
+                return Err(ServiceError::InvalidArgument("Fuzzy name search needs a name".to_string()));
+            }
+            Box::new(SimilarTo::new(display_name, text.clone().into_sql::<Text>()))
+        }
+        Filter::GroupId(filter_group_id) => Box::new(
+            channel_id.eq_any(
+                bpp_groups_users::table
+                    .filter(bpp_groups_users::group_id.eq(*filter_group_id))
+                    .select(bpp_groups_users::channel_id),
+            ),
+        ),
+        // Users who haven't reached any rank yet have the rank id 0, like the default rank in the API
+        Filter::RankId(0) => Box::new(rank_id.is_null()),
+        Filter::RankId(filter_rank_id) => Box::new(rank_id.eq(*filter_rank_id)),
+    };
+    Ok(predicate)
+}
+
+/// Translates a filter expression sent by a client into a single predicate
+pub fn expression_predicate(expression: &BppUserFilterExpression) -> Result<UserPredicate, ServiceError> {
+    nested_expression_predicate(expression, 0)
+}
+
+fn nested_expression_predicate(expression: &BppUserFilterExpression, depth: usize) -> Result<UserPredicate, ServiceError> {
+    if depth > MAX_EXPRESSION_DEPTH {
+        return Err(ServiceError::InvalidArgument("User filter expression is nested too deeply".to_string()));
+    }
+
+    match &expression.node {
+        Some(Node::Leaf(filter)) => filter_predicate(filter),
+        Some(Node::And(operands)) => {
+            let mut operands = operand_predicates(operands, depth)?.into_iter();
+            let first = operands.next().unwrap();
+            Ok(operands.fold(first, |predicate, operand| Box::new(predicate.and(operand))))
+        }
+        Some(Node::Or(operands)) => {
+            let mut operands = operand_predicates(operands, depth)?.into_iter();
+            let first = operands.next().unwrap();
+            Ok(operands.fold(first, |predicate, operand| Box::new(predicate.or(operand))))
+        }
+        Some(Node::Not(operand)) => Ok(Box::new(not(nested_expression_predicate(operand, depth + 1)?))),
+        None => Err(ServiceError::InvalidArgument("Empty user filter expression".to_string())),
+    }
+}
+
+/// Translates the operands of an `and` or `or` node, of which there has to be at least one
+fn operand_predicates(operands: &Operands, depth: usize) -> Result<Vec<UserPredicate>, ServiceError> {
+    if operands.operands.is_empty() {
+        return Err(ServiceError::InvalidArgument("User filter expression without operands".to_string()));
+    }
+    operands
+        .operands
+        .iter()
+        .map(|operand| nested_expression_predicate(operand, depth + 1))
+        .collect()
+}
+
+/// Builds the query for all users matching the request, which are the users matching every one of the filters as
+/// well as the filter expression
+pub fn filtered_users(request: &BppUserFilters) -> Result<bpp_users::BoxedQuery<'static, Pg>, ServiceError> {
+    let mut query = bpp_users::table.filter(bpp_users::deleted_at.is_null()).into_boxed();
+    for filter in &request.filters {
+        query = query.filter(filter_predicate(filter)?);
+    }
+    if let Some(expression) = &request.expression {
+        query = query.filter(expression_predicate(expression)?);
+    }
+    Ok(query)
+}
+
+/// The sorting of the rank stored for a user, users without a rank sort below all others
+const RANK_SORTING_SQL: &str = "COALESCE((SELECT bpp_ranks.rank_sorting FROM bpp_ranks \
+    WHERE bpp_ranks.rank_id = bpp_users.rank_id), -2147483648)";
+/// The highest sorting of the groups of a user, users without a group sort below all others
+const GROUP_SORTING_SQL: &str = "COALESCE((SELECT MAX(bpp_groups.group_sorting) FROM bpp_groups \
+    INNER JOIN bpp_groups_users ON bpp_groups_users.group_id = bpp_groups.group_id \
+    WHERE bpp_groups_users.channel_id = bpp_users.channel_id), -2147483648)";
+
+fn order_by_key(
+    query: bpp_users::BoxedQuery<'static, Pg>,
+    field: Field,
+    descending: bool,
+) -> bpp_users::BoxedQuery<'static, Pg> {
+    use crate::schema::bpp_users::dsl::*;
+    macro_rules! order {
+        ($expression:expr) => {
+            if descending {
+                query.then_order_by($expression.desc())
+            } else {
+                query.then_order_by($expression.asc())
+            }
+        };
+    }
+
+    match field {
+        Field::Hours => order!(hours_seconds),
+        Field::Money => order!(money),
+        Field::DisplayName => order!(display_name),
+        Field::FirstSeenAt => order!(first_seen_at),
+        Field::LastSeenAt => order!(last_seen_at),
+        Field::Rank => order!(sql::<Int4>(RANK_SORTING_SQL)),
+        Field::GroupSorting => order!(sql::<Int4>(GROUP_SORTING_SQL)),
+        Field::ChannelId => order!(channel_id),
+    }
+}
+
+/// Orders the query by the requested sort keys or, if there are none, by the legacy sorting field. Users which are
+/// equal in all of them are ordered by their channel id, so the order and therefore the pages are stable.
+pub fn sorted_users(
+    mut query: bpp_users::BoxedQuery<'static, Pg>,
+    request: &BppUserFilters,
+) -> Result<bpp_users::BoxedQuery<'static, Pg>, ServiceError> {
+    if request.sort_keys.is_empty() {
+        query = match request.sorting() {
+            SortingFields::HoursAsc => order_by_key(query, Field::Hours, false),
+            SortingFields::HoursDesc => order_by_key(query, Field::Hours, true),
+            SortingFields::MoneyAsc => order_by_key(query, Field::Money, false),
+            SortingFields::MoneyDesc => order_by_key(query, Field::Money, true),
+            SortingFields::Default => query,
+        };
+    }
+    for sort_key in &request.sort_keys {
+        let field = Field::from_i32(sort_key.field).ok_or_else(|| ServiceError::InvalidArgument("Unknown sort field".to_string()))?;
+        query = order_by_key(query, field, sort_key.descending);
+    }
+    Ok(query.then_order_by(bpp_users::channel_id.asc()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::debug_query;
+
+    fn leaf(filter: Filter) -> BppUserFilterExpression {
+        BppUserFilterExpression {
+            node: Some(Node::Leaf(BppUserFilter { filter: Some(filter) })),
+        }
+    }
+
+    fn node(node: Node) -> BppUserFilterExpression {
+        BppUserFilterExpression { node: Some(node) }
+    }
+
+    fn operands(operands: Vec<BppUserFilterExpression>) -> Operands {
+        Operands { operands }
+    }
+
+    /// Nests a leaf in `depth` negations
+    fn negated(depth: usize) -> BppUserFilterExpression {
+        (0..depth).fold(leaf(Filter::Hours(1)), |expression, _| node(Node::Not(Box::new(expression))))
+    }
+
+    fn sql(expression: &BppUserFilterExpression) -> String {
+        let query = bpp_users::table.filter(expression_predicate(expression).ok().unwrap());
+        debug_query::<Pg, _>(&query).to_string()
+    }
+
+    fn invalid_argument(result: Result<UserPredicate, ServiceError>) -> String {
+        match result {
+            Err(ServiceError::InvalidArgument(message)) => message,
+            Err(e) => panic!("Expected an invalid argument, got {:?}", e),
+            Ok(_) => panic!("Expected an invalid argument, got a predicate"),
+        }
+    }
+
+    #[test]
+    fn and_without_operands_is_rejected() {
+        let expression = node(Node::And(operands(vec![])));
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "User filter expression without operands");
+    }
+
+    #[test]
+    fn or_without_operands_is_rejected() {
+        let expression = node(Node::Or(operands(vec![])));
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "User filter expression without operands");
+    }
+
+    #[test]
+    fn nested_empty_operands_are_rejected() {
+        let expression = node(Node::And(operands(vec![leaf(Filter::Hours(1)), node(Node::Or(operands(vec![])))])));
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "User filter expression without operands");
+    }
+
+    #[test]
+    fn expression_without_a_node_is_rejected() {
+        let expression = BppUserFilterExpression { node: None };
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "Empty user filter expression");
+    }
+
+    #[test]
+    fn operands_are_combined() {
+        let and = node(Node::And(operands(vec![leaf(Filter::Hours(1)), leaf(Filter::Hours(2))])));
+        assert!(sql(&and).contains(" AND "));
+        let or = node(Node::Or(operands(vec![leaf(Filter::Hours(1)), leaf(Filter::Hours(2))])));
+        assert!(sql(&or).contains(" OR "));
+    }
+
+    #[test]
+    fn expressions_up_to_the_maximum_depth_are_accepted() {
+        assert!(expression_predicate(&negated(MAX_EXPRESSION_DEPTH)).is_ok());
+    }
+
+    #[test]
+    fn expressions_beyond_the_maximum_depth_are_rejected() {
+        assert_eq!(
+            invalid_argument(expression_predicate(&negated(MAX_EXPRESSION_DEPTH + 1))),
+            "User filter expression is nested too deeply"
+        );
+    }
+
+    #[test]
+    fn unknown_comparison_operators_are_rejected() {
+        let comparison = Int64Comparison { operator: 42, value: 1, upper: 0 };
+        let expression = leaf(Filter::HoursComparison(comparison));
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "Unknown comparison operator");
+    }
+
+    #[test]
+    fn ranges_ending_below_their_start_are_rejected() {
+        let comparison = Int64Comparison { operator: ComparisonOperator::Between as i32, value: 2, upper: 1 };
+        let expression = leaf(Filter::MoneyComparison(comparison));
+        assert_eq!(
+            invalid_argument(expression_predicate(&expression)),
+            "Upper bound of a range must not be below its lower bound"
+        );
+    }
+
+    #[test]
+    fn timestamp_ranges_need_an_upper_bound() {
+        let comparison = TimestampComparison {
+            operator: ComparisonOperator::Between as i32,
+            value: Some(prost_types::Timestamp { seconds: 0, nanos: 0 }),
+            upper: None,
+        };
+        let expression = leaf(Filter::FirstSeenAt(comparison));
+        assert_eq!(invalid_argument(expression_predicate(&expression)), "Timestamp range without an upper bound");
+    }
+}
@@ -0,0 +1,357 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use tonic::transport::Channel;
+use tonic::{Request, Streaming};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::cache::{Cache, Snapshot, UserGrants};
+use crate::events::{self, EventBus};
+use crate::ledger::{self, TransactionReason};
+use crate::models::{naive_to_timestamp, User};
+use crate::sessions::{self, BroadcastState};
+use crate::settings::{Accrual, Settings};
+use crate::youtubeservice::you_tube_service_client::YouTubeServiceClient;
+use crate::youtubeservice::YouTubeChatMessage;
+use crate::{money, ranks, user_change_events, userservice, DbPool, Void};
+
+/// The name the health of message ingestion is reported under by the health service
+pub const HEALTH_SERVICE_NAME: &str = "userservice.Ingestion";
+/// How long to wait before the first attempt to reconnect
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest wait between two attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+struct IngestionState {
+    /// Whether messages are ingested at all
+    enabled: bool,
+    connected: bool,
+    /// When the current connection was established
+    connected_since: Option<NaiveDateTime>,
+    /// When the last connection was lost, or when the service started if it never connected
+    down_since: Option<NaiveDateTime>,
+    last_message_at: Option<NaiveDateTime>,
+    /// Failed attempts to connect since the last connection was lost or the service started
+    reconnect_attempts: i32,
+    /// Why the last connection was lost or the last attempt to connect failed, while not connected
+    last_error: Option<String>,
+}
+
+/// Whether messages are currently being received from the youtubeservice, shared with the API
+#[derive(Clone)]
+pub struct Ingestion {
+    state: Arc<RwLock<IngestionState>>,
+}
+
+impl Ingestion {
+    pub fn new() -> Ingestion {
+        Ingestion::with_state(true, Some(Utc::now().naive_utc()))
+    }
+
+    /// The status of a service which only serves the API
+    pub fn disabled() -> Ingestion {
+        Ingestion::with_state(false, None)
+    }
+
+    fn with_state(enabled: bool, down_since: Option<NaiveDateTime>) -> Ingestion {
+        Ingestion {
+            state: Arc::new(RwLock::new(IngestionState {
+                enabled,
+                connected: false,
+                connected_since: None,
+                down_since,
+                last_message_at: None,
+                reconnect_attempts: 0,
+                last_error: None,
+            })),
+        }
+    }
+
+    fn connected(&self) {
+        let now = Utc::now().naive_utc();
+        let mut state = self.state.write().unwrap();
+        // Only a lost connection leaves a gap, not the time it took to connect for the first time
+        if let (Some(down_since), Some(_)) = (state.down_since, state.connected_since) {
+            warn!(
+                "Users weren't tracked for {}s between {} and {}, messages sent in between were missed",
+                (now - down_since).num_seconds(),
+                down_since,
+                now
+            );
+        }
+        state.connected = true;
+        state.connected_since = Some(now);
+        state.down_since = None;
+        state.reconnect_attempts = 0;
+        state.last_error = None;
+    }
+
+    fn disconnected(&self, error: String) {
+        let mut state = self.state.write().unwrap();
+        if state.connected {
+            state.connected = false;
+            state.down_since = Some(Utc::now().naive_utc());
+        } else {
+            state.reconnect_attempts += 1;
+        }
+        state.last_error = Some(error);
+    }
+
+    fn message_received(&self, now: NaiveDateTime) {
+        self.state.write().unwrap().last_message_at = Some(now);
+    }
+
+    pub fn to_userservice_status(&self) -> userservice::IngestionStatus {
+        let state = self.state.read().unwrap();
+        userservice::IngestionStatus {
+            enabled: state.enabled,
+            connected: state.connected,
+            connected_since: state.connected_since.as_ref().map(naive_to_timestamp),
+            down_since: state.down_since.as_ref().map(naive_to_timestamp),
+            last_message_at: state.last_message_at.as_ref().map(naive_to_timestamp),
+            reconnect_attempts: state.reconnect_attempts,
+            last_error: state.last_error.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl Default for Ingestion {
+    fn default() -> Ingestion {
+        Ingestion::new()
+    }
+}
+
+/// Subscribes to the messages of the youtubeservice and tracks their senders, forever.
+///
+/// Whenever the connection can't be established or the stream ends, this reconnects with exponential backoff. The
+/// health service reports ingestion as serving only while the stream is connected.
+pub async fn supervise(
+    youtube_address: String,
+    pool: DbPool,
+    event_bus: EventBus,
+    cache: Cache,
+    ingestion: Ingestion,
+    mut health_reporter: HealthReporter,
+) {
+    health_reporter.set_service_status(HEALTH_SERVICE_NAME, ServingStatus::NotServing).await;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let error = match subscribe(&youtube_address).await {
+            Ok(stream) => {
+                info!("Connected to youtubeservice! Time to go on a hunt!");
+                ingestion.connected();
+                health_reporter.set_service_status(HEALTH_SERVICE_NAME, ServingStatus::Serving).await;
+                backoff = INITIAL_BACKOFF;
+
+                let error = match fetch_users_from_messages(stream, &pool, &event_bus, &cache, &ingestion).await {
+                    Ok(()) => "the message stream ended".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                health_reporter.set_service_status(HEALTH_SERVICE_NAME, ServingStatus::NotServing).await;
+                warn!("Lost connection to youtubeservice ({}), reconnecting in {}s", error, backoff.as_secs());
+                error
+            }
+            Err(e) => {
+                warn!("Failed to connect to youtubeservice ({}), retrying in {}s", e, backoff.as_secs());
+                e.to_string()
+            }
+        };
+
+        ingestion.disconnected(error);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn subscribe(
+    youtube_address: &str,
+) -> Result<Streaming<YouTubeChatMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut youtube_client: YouTubeServiceClient<Channel> =
+        YouTubeServiceClient::connect(youtube_address.to_string()).await?;
+    Ok(youtube_client.subscribe_messages(Request::new(())).await?.into_inner())
+}
+
+/// Updates the hours and money of the user and returns the money paid out.
+///
+/// Fails if their hours or payout don't fit into the stored range.
+fn calculate_hours_and_money(
+    user: &mut User,
+    now: &NaiveDateTime,
+    settings: Settings,
+    accrual: Accrual,
+    snapshot: &Snapshot,
+    grants: &UserGrants,
+) -> Result<BigDecimal, String> {
+    let new_seconds = (*now - user.last_seen_at).num_seconds();
+    debug!("Between the last time the user was seen and now, {} seconds have passed", new_seconds);
+    let out_of_range = || "Their hours or money would exceed the supported range".to_string();
+    if accrual.hours {
+        let new_hours_seconds = user.hours_seconds.checked_add(new_seconds).ok_or_else(out_of_range)?;
+        debug!(
+            "Updating hours of {} ({}) from {}s to {}s",
+            user.channel_id,
+            user.display_name,
+            user.hours_seconds,
+            new_hours_seconds
+        );
+
+        user.hours_seconds = new_hours_seconds;
+    }
+    if !accrual.money {
+        return Ok(BigDecimal::zero());
+    }
+
+    // Grant x money per minute, rounded down to whole minor units
+    let mut money_per_minute = settings.default_payout as i64;
+    let user_groups = snapshot.groups_for_user(grants, &user.channel_id);
+    for group in user_groups {
+        money_per_minute += group.bonus_payout as i64;
+    }
+    let payout_minor_units = money_per_minute
+        .checked_mul(money::MINOR_UNITS_PER_UNIT)
+        .and_then(|per_minute| per_minute.checked_mul(new_seconds))
+        .ok_or_else(out_of_range)?
+        / 60;
+
+    let payout = money::from_minor_units(payout_minor_units);
+    let new_money = &user.money + &payout;
+    if !money::is_storable(&new_money) {
+        warn!(
+            "Not paying out {} ({}), their balance would exceed the supported range",
+            user.channel_id, user.display_name
+        );
+        return Ok(BigDecimal::zero());
+    }
+    debug!(
+        "Updating money of {} ({}) from {} to {}",
+        user.channel_id, user.display_name, user.money, new_money
+    );
+    user.money = new_money;
+
+    Ok(payout)
+}
+
+/// Tracks the users sending the messages of the stream until the stream ends or fails.
+///
+/// Only errors of the stream itself end it, a message which can't be tracked is logged and skipped, even if tracking
+/// it panicked.
+async fn fetch_users_from_messages(
+    mut stream: Streaming<YouTubeChatMessage>,
+    pool: &DbPool,
+    event_bus: &EventBus,
+    cache: &Cache,
+    ingestion: &Ingestion,
+) -> Void {
+    while let Some(message) = stream.message().await? {
+        let now = Utc::now().naive_utc();
+        ingestion.message_received(now);
+        let tracked = panic::catch_unwind(AssertUnwindSafe(|| track_message(&message, now, pool, event_bus, cache)));
+        match tracked {
+            Ok(Ok(changes)) => event_bus.publish_all(changes),
+            Ok(Err(e)) => error!("Failed to track message {} of {}: {}", message.message_id, message.channel_id, e),
+            Err(_) => error!("Tracking message {} of {} panicked", message.message_id, message.channel_id),
+        }
+    }
+
+    Ok(())
+}
+
+/// Updates the user sending a message, pays them out and tracks their session, returns the events describing the
+/// changes
+fn track_message(
+    message: &YouTubeChatMessage,
+    now: NaiveDateTime,
+    pool: &DbPool,
+    event_bus: &EventBus,
+    cache: &Cache,
+) -> Result<Vec<userservice::UserEvent>, Box<dyn std::error::Error>> {
+    let conn = pool.get()?;
+    let settings = Settings::new()?;
+    let snapshot = cache.get(&conn)?;
+
+    let changes = conn.transaction::<_, Box<dyn std::error::Error>, _>(|| {
+        // Follow the broadcast state if the youtubeservice knows it, otherwise keep the one set through the API
+        let reported_state = message
+            .broadcast
+            .as_ref()
+            .and_then(|status| Some((BroadcastState::from_youtube(status.state())?, status)));
+        // Most messages report the state we are already in, only changes need to lock the running broadcast
+        let running = sessions::running_broadcast(&conn)?;
+        let broadcast = match reported_state {
+            Some((state, status)) if !sessions::is_in_state(running.as_ref(), state, &status.video_id) => {
+                sessions::set_broadcast_state(state, &status.title, &status.video_id, now, &conn)?
+            }
+            _ => running,
+        };
+        let accrual = settings.accrual(sessions::current_state(broadcast.as_ref()));
+
+        // Lock the user, so payouts can't race with transfers
+        let previous = User::get_for_update(&message.channel_id, &conn)?;
+        if previous.as_ref().map(User::is_deleted).unwrap_or(false) {
+            debug!("Skipping deleted user {}", &message.channel_id);
+            return Ok(Vec::new());
+        }
+        let mut user = match &previous {
+            Some(user) => {
+                debug!("Updating existing user {}", &message.channel_id);
+                user.clone()
+            }
+            None => {
+                debug!("Creating new user {}", &message.channel_id);
+                User::new(
+                    message.channel_id.clone(),
+                    message.display_name.clone(),
+                    0,
+                    BigDecimal::zero(),
+                    now,
+                    now,
+                )
+            }
+        };
+
+        user.display_name = message.display_name.clone();
+
+        // Determine if user was active before this message and if so, update the hours
+        // if the user has been last seen less than the configured timeframe, update the hours
+        let active_time = chrono::Duration::seconds(settings.active_time as i64);
+        let mut payout = BigDecimal::zero();
+        if user.last_seen_at + active_time > now {
+            let grants = UserGrants::load(&[user.channel_id.as_str()], &conn)?;
+            payout = calculate_hours_and_money(&mut user, &now, settings, accrual, &snapshot, &grants)?;
+        }
+        user.last_seen_at = now;
+
+        // Update the user, book the payout in their ledger and track the session the message belongs to
+        user.save_to_database(&conn)?;
+        if !payout.is_zero() {
+            ledger::record(&user.channel_id, payout, TransactionReason::Payout, ledger::SYSTEM_ACTOR, &conn)?;
+        }
+        let broadcast_id = broadcast.map(|broadcast| broadcast.broadcast_id);
+        sessions::record_message(&user.channel_id, broadcast_id, now, active_time, &conn)?;
+
+        let rank_changes = if previous.is_some() {
+            ranks::refresh_ranks(Some(&user.channel_id), &[], &conn)?
+        } else {
+            ranks::assign_initial_rank(&user.channel_id, &conn)?;
+            Vec::new()
+        };
+        for change in &rank_changes {
+            info!("{} ({}) reached rank {}", user.channel_id, user.display_name, change.new_rank_name);
+        }
+
+        // Describing the change costs a few queries, so skip it while nobody is listening
+        if !event_bus.has_subscribers() {
+            return Ok(Vec::new());
+        }
+        let mut changes = user_change_events(previous.as_ref(), &user, TransactionReason::Payout, &conn)?;
+        changes.extend(rank_changes.into_iter().map(events::rank_changed));
+        Ok(changes)
+    })?;
+    Ok(changes)
+}
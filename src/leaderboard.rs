@@ -0,0 +1,147 @@
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text, Varchar};
+
+use crate::error::ServiceError;
+use crate::schema::bpp_users;
+use crate::userservice::{LeaderboardEntry, LeaderboardStat};
+
+/// How many entries a leaderboard has when the request doesn't say
+pub const DEFAULT_LEADERBOARD_SIZE: i64 = 10;
+/// The largest leaderboard a request may ask for
+pub const MAX_LEADERBOARD_SIZE: i64 = 500;
+/// The most neighbours above and below a user a position lookup may ask for
+pub const MAX_NEIGHBOURS: i64 = 50;
+
+/// A user on a leaderboard, users with the same value share a position
+#[derive(QueryableByName)]
+pub struct LeaderboardRow {
+    #[sql_type = "BigInt"]
+    pub position: i64,
+    #[sql_type = "Varchar"]
+    pub channel_id: String,
+    #[sql_type = "Varchar"]
+    pub display_name: String,
+    #[sql_type = "BigInt"]
+    pub value: i64,
+}
+
+/// Turns the limit of a request into the number of entries a leaderboard has
+pub fn leaderboard_size(limit: i32) -> Result<i64, ServiceError> {
+    match limit {
+        limit if limit < 0 => Err(ServiceError::InvalidArgument("Limit must not be negative".to_string())),
+        0 => Ok(DEFAULT_LEADERBOARD_SIZE),
+        limit => Ok((limit as i64).min(MAX_LEADERBOARD_SIZE)),
+    }
+}
+
+/// Returns the SQL selecting every user on a leaderboard with the value they are ranked by, as `sort_value`, and the
+/// value as it is sent to clients, as `value`
+fn users_sql(stat: LeaderboardStat) -> String {
+    match stat {
+        // The leaderboard indexes cover the raw columns together with the channel id
+        LeaderboardStat::Hours => column_users_sql("hours_seconds", "bpp_users.hours_seconds"),
+        // Money is sent in minor units, like everywhere else in the API
+        LeaderboardStat::Money => column_users_sql("money", "(bpp_users.money * 100)::BIGINT"),
+        LeaderboardStat::WatchTime => session_users_sql("SUM(bpp_sessions.duration_seconds)"),
+        LeaderboardStat::Messages => session_users_sql("SUM(bpp_sessions.message_count)"),
+        LeaderboardStat::Sessions => session_users_sql("COUNT(*)"),
+    }
+}
+
+fn column_users_sql(column: &str, value: &str) -> String {
+    format!(
+        "SELECT bpp_users.{column} AS sort_value, bpp_users.channel_id, bpp_users.display_name, {value} AS value
+        FROM bpp_users
+        WHERE bpp_users.deleted_at IS NULL",
+        column = column,
+        value = value
+    )
+}
+
+/// Users without any sessions are ranked with a value of 0
+fn session_users_sql(aggregate: &str) -> String {
+    format!(
+        "SELECT COALESCE(stats.value, 0) AS sort_value, bpp_users.channel_id, bpp_users.display_name,
+            COALESCE(stats.value, 0) AS value
+        FROM bpp_users
+        LEFT JOIN (
+            SELECT bpp_sessions.channel_id, {aggregate}::BIGINT AS value
+            FROM bpp_sessions
+            GROUP BY bpp_sessions.channel_id
+        ) stats ON stats.channel_id = bpp_users.channel_id
+        WHERE bpp_users.deleted_at IS NULL",
+        aggregate = aggregate
+    )
+}
+
+/// Counts the users on a leaderboard, which are all users that weren't deleted
+fn count_users(conn: &PgConnection) -> QueryResult<i64> {
+    bpp_users::table.filter(bpp_users::deleted_at.is_null()).count().get_result(conn)
+}
+
+/// Loads the top users of a leaderboard, ordered by channel id within a position, together with the number of users
+/// on the whole leaderboard.
+///
+/// Every user ranked above one of the top users is among them, so their positions can be computed from the top users
+/// alone.
+pub fn top_users(stat: LeaderboardStat, limit: i64, conn: &PgConnection) -> QueryResult<(i64, Vec<LeaderboardRow>)> {
+    let query = format!(
+        "SELECT RANK() OVER (ORDER BY top.sort_value DESC) AS position, top.channel_id, top.display_name, top.value
+        FROM (
+            SELECT users.sort_value, users.channel_id, users.display_name, users.value
+            FROM ({users}) users
+            ORDER BY users.sort_value DESC, users.channel_id ASC
+            LIMIT $1
+        ) top
+        ORDER BY top.sort_value DESC, top.channel_id ASC",
+        users = users_sql(stat)
+    );
+    let total = count_users(conn)?;
+    let rows = diesel::sql_query(query).bind::<BigInt, _>(limit).load(conn)?;
+    Ok((total, rows))
+}
+
+/// Loads a user and up to `neighbours` users directly above and below them on a leaderboard, ordered from the top and
+/// by channel id within a position, together with the number of users on the whole leaderboard.
+///
+/// Returns no rows if the user doesn't exist or was deleted.
+pub fn user_with_neighbours(
+    stat: LeaderboardStat,
+    channel_id: &str,
+    neighbours: i64,
+    conn: &PgConnection,
+) -> QueryResult<(i64, Vec<LeaderboardRow>)> {
+    let query = format!(
+        "WITH ranked AS (
+            SELECT RANK() OVER (ORDER BY users.sort_value DESC) AS position,
+                ROW_NUMBER() OVER (ORDER BY users.sort_value DESC, users.channel_id ASC) AS row_number,
+                users.channel_id,
+                users.display_name,
+                users.value
+            FROM ({users}) users
+        )
+        SELECT ranked.position, ranked.channel_id, ranked.display_name, ranked.value
+        FROM ranked, ranked target
+        WHERE target.channel_id = $1
+            AND ranked.row_number BETWEEN target.row_number - $2 AND target.row_number + $2
+        ORDER BY ranked.row_number ASC",
+        users = users_sql(stat)
+    );
+    let total = count_users(conn)?;
+    let rows = diesel::sql_query(query)
+        .bind::<Text, _>(channel_id)
+        .bind::<BigInt, _>(neighbours)
+        .load(conn)?;
+    Ok((total, rows))
+}
+
+impl From<LeaderboardRow> for LeaderboardEntry {
+    fn from(row: LeaderboardRow) -> LeaderboardEntry {
+        LeaderboardEntry {
+            position: row.position,
+            channel_id: row.channel_id,
+            display_name: row.display_name,
+            value: row.value,
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+
+use crate::audit::{self, Target};
+use crate::error::ServiceError;
+use crate::models::{naive_to_timestamp, InsertTransaction, Transaction, User};
+use crate::money;
+use crate::paging::Page;
+use crate::schema::bpp_transactions;
+use crate::userservice::bpp_transaction::Reason;
+use crate::userservice::BppTransaction;
+
+/// The actor of changes the service makes on its own, like watch time payouts
+pub const SYSTEM_ACTOR: &str = "userservice";
+
+/// Why the balance of a user changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionReason {
+    /// The balance a user had when the ledger was introduced or the user was created
+    Opening,
+    /// Money earned by watching the stream
+    Payout,
+    /// A change made by an administrator or another service
+    Adjustment,
+    /// Money sent to or received from another user
+    Transfer,
+    /// Money spent on something
+    Purchase,
+    /// Money paid on top of a transfer
+    Fee,
+}
+
+impl TransactionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionReason::Opening => "opening",
+            TransactionReason::Payout => "payout",
+            TransactionReason::Adjustment => "adjustment",
+            TransactionReason::Transfer => "transfer",
+            TransactionReason::Purchase => "purchase",
+            TransactionReason::Fee => "fee",
+        }
+    }
+
+    pub fn parse(reason: &str) -> Option<TransactionReason> {
+        match reason {
+            "opening" => Some(TransactionReason::Opening),
+            "payout" => Some(TransactionReason::Payout),
+            "adjustment" => Some(TransactionReason::Adjustment),
+            "transfer" => Some(TransactionReason::Transfer),
+            "purchase" => Some(TransactionReason::Purchase),
+            "fee" => Some(TransactionReason::Fee),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransactionReason> for Reason {
+    fn from(reason: TransactionReason) -> Reason {
+        match reason {
+            TransactionReason::Opening => Reason::Opening,
+            TransactionReason::Payout => Reason::Payout,
+            TransactionReason::Adjustment => Reason::Adjustment,
+            TransactionReason::Transfer => Reason::Transfer,
+            TransactionReason::Purchase => Reason::Purchase,
+            TransactionReason::Fee => Reason::Fee,
+        }
+    }
+}
+
+/// Appends an entry to the ledger of a user.
+///
+/// This doesn't touch the balance of the user, callers are expected to change both inside the same transaction.
+pub fn record(
+    channel_id: &str,
+    amount: BigDecimal,
+    reason: TransactionReason,
+    actor: &str,
+    conn: &PgConnection,
+) -> QueryResult<Transaction> {
+    let transaction = InsertTransaction {
+        channel_id: channel_id.to_string(),
+        amount,
+        reason: reason.as_str().to_string(),
+        actor: actor.to_string(),
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(bpp_transactions::table)
+        .values(&transaction)
+        .get_result(conn)
+}
+
+/// Why a transfer was rejected
+pub enum TransferError {
+    UserNotFound(String),
+    InsufficientFunds,
+    /// The balance of the recipient would exceed the range of the money columns
+    BalanceOutOfRange(String),
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for TransferError {
+    fn from(error: diesel::result::Error) -> TransferError {
+        TransferError::Database(error)
+    }
+}
+
+/// Moves money from one user to another, the sender pays the fee on top of the amount.
+///
+/// Both users are locked for the duration of the transfer, so the balance check can't race with payouts or other
+/// transfers. Returns the sender and the recipient after the transfer, which is recorded in the audit log under `rpc`.
+pub fn transfer(
+    sender_id: &str,
+    recipient_id: &str,
+    amount: &BigDecimal,
+    fee: &BigDecimal,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> Result<(User, User), TransferError> {
+    conn.transaction(|| {
+        let mut users = User::get_many_for_update(&[sender_id, recipient_id], conn)?;
+        users.retain(|user| !user.is_deleted());
+        let sender = match users.iter().find(|user| user.channel_id == sender_id) {
+            Some(sender) => sender,
+            None => return Err(TransferError::UserNotFound(sender_id.to_string())),
+        };
+        if !users.iter().any(|user| user.channel_id == recipient_id) {
+            return Err(TransferError::UserNotFound(recipient_id.to_string()));
+        }
+
+        let total = amount + fee;
+        if sender.money < total {
+            return Err(TransferError::InsufficientFunds);
+        }
+
+        let previous_sender_money = sender.money.clone();
+        let previous_recipient_money = users
+            .iter()
+            .find(|user| user.channel_id == recipient_id)
+            .map(|recipient| recipient.money.clone())
+            .unwrap_or_else(BigDecimal::zero);
+        // The sender can't go below zero, but the recipient could grow past what the database holds
+        if !money::is_storable(&(&previous_recipient_money + amount)) {
+            return Err(TransferError::BalanceOutOfRange(recipient_id.to_string()));
+        }
+        let sender = User::add_money(sender_id, &-total, conn)?;
+        let recipient = User::add_money(recipient_id, amount, conn)?;
+        for (user, previous_money) in &[(&sender, previous_sender_money), (&recipient, previous_recipient_money)] {
+            audit::record(
+                actor,
+                rpc,
+                Target::User(user.channel_id.clone()),
+                Some(audit::money_value(previous_money)),
+                Some(audit::money_value(&user.money)),
+                conn,
+            )?;
+        }
+        record(sender_id, -amount.clone(), TransactionReason::Transfer, actor, conn)?;
+        if !fee.is_zero() {
+            record(sender_id, -fee.clone(), TransactionReason::Fee, actor, conn)?;
+        }
+        record(recipient_id, amount.clone(), TransactionReason::Transfer, actor, conn)?;
+
+        Ok((sender, recipient))
+    })
+}
+
+/// Adds a possibly negative amount to the money of a user and books the change that was actually applied, which
+/// differs from the delta if the balance was floored at zero. Returns the user before and after the adjustment, which
+/// is recorded in the audit log under `rpc`.
+pub fn adjust(
+    channel_id: &str,
+    delta: &BigDecimal,
+    floor_at_zero: bool,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> Result<(User, User), ServiceError> {
+    conn.transaction(|| {
+        let previous = match User::get_for_update(channel_id, conn)? {
+            Some(previous) if !previous.is_deleted() => previous,
+            _ => return Err(ServiceError::NotFound("User not found".to_string())),
+        };
+        let mut balance = &previous.money + delta;
+        if floor_at_zero && balance < BigDecimal::zero() {
+            balance = BigDecimal::zero();
+        }
+        if !money::is_storable(&balance) {
+            return Err(ServiceError::FailedPrecondition(format!(
+                "The balance of {} would exceed the supported range",
+                channel_id
+            )));
+        }
+        let user = User::adjust_money(channel_id, delta, floor_at_zero, conn)?;
+
+        let applied = &user.money - &previous.money;
+        if !applied.is_zero() {
+            record(channel_id, applied, TransactionReason::Adjustment, actor, conn)?;
+        }
+        audit::record(
+            actor,
+            rpc,
+            Target::User(channel_id.to_string()),
+            Some(audit::money_value(&previous.money)),
+            Some(audit::money_value(&user.money)),
+            conn,
+        )?;
+        Ok((previous, user))
+    })
+}
+
+/// Returns the balance of a user as derived from their ledger
+pub fn ledger_balance(channel_id: &str, conn: &PgConnection) -> QueryResult<BigDecimal> {
+    let balance: Option<BigDecimal> = bpp_transactions::table
+        .filter(bpp_transactions::channel_id.eq(channel_id))
+        .select(diesel::dsl::sum(bpp_transactions::amount))
+        .first(conn)?;
+    Ok(balance.unwrap_or_else(BigDecimal::zero))
+}
+
+fn filtered_transactions(
+    channel_id: &str,
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+) -> bpp_transactions::BoxedQuery<'static, Pg> {
+    let mut query = bpp_transactions::table.into_boxed();
+    if !channel_id.is_empty() {
+        query = query.filter(bpp_transactions::channel_id.eq(channel_id.to_string()));
+    }
+    if let Some(since) = since {
+        query = query.filter(bpp_transactions::created_at.ge(since));
+    }
+    if let Some(until) = until {
+        query = query.filter(bpp_transactions::created_at.lt(until));
+    }
+    query
+}
+
+/// Loads one page of ledger entries, newest first, together with the total number of matching entries.
+///
+/// An empty channel id matches the entries of all users.
+pub fn get_transactions(
+    channel_id: &str,
+    since: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    page: &Page,
+    conn: &PgConnection,
+) -> QueryResult<(i64, Vec<Transaction>)> {
+    let total = filtered_transactions(channel_id, since, until)
+        .count()
+        .get_result(conn)?;
+    let transactions = filtered_transactions(channel_id, since, until)
+        .order((bpp_transactions::created_at.desc(), bpp_transactions::transaction_id.desc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<Transaction>(conn)?;
+    Ok((total, transactions))
+}
+
+impl From<Transaction> for BppTransaction {
+    fn from(transaction: Transaction) -> BppTransaction {
+        let reason: Reason = TransactionReason::parse(&transaction.reason)
+            .unwrap_or(TransactionReason::Adjustment)
+            .into();
+        BppTransaction {
+            transaction_id: transaction.transaction_id,
+            channel_id: transaction.channel_id,
+            amount: money::to_minor_units(&transaction.amount),
+            reason: reason as i32,
+            actor: transaction.actor,
+            created_at: Some(naive_to_timestamp(&transaction.created_at)),
+        }
+    }
+}
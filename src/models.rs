@@ -1,13 +1,21 @@
-use std::ops::Deref;
+use std::convert::TryFrom;
 
 use super::schema::*;
 use super::userservice::{BppUser, BppGroup, CreateBppGroup, BppRank, CreateBppRank};
+use crate::audit;
+use crate::cache::{Snapshot, UserGrants};
+use crate::error::ServiceError;
+use crate::money;
+use crate::paging::Page;
+use crate::permissions::PermissionNode;
 use crate::{bpp_foreign_model_impl, bpp_model_impl};
-use chrono::NaiveDateTime;
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use prost_types::Duration;
+use rand::Rng;
 
-#[derive(Queryable, AsChangeset, Identifiable)]
+#[derive(Queryable, AsChangeset, Identifiable, Clone)]
 #[primary_key(rank_id)]
 #[table_name = "bpp_ranks"]
 pub struct Rank {
@@ -27,7 +35,7 @@ pub struct InsertRank {
     pub hour_requirement_nanos: i32,
 }
 
-#[derive(Queryable, AsChangeset, Identifiable, PartialEq, Eq)]
+#[derive(Queryable, AsChangeset, Identifiable, PartialEq, Eq, Clone)]
 #[primary_key(group_id)]
 #[table_name = "bpp_groups"]
 pub struct Group {
@@ -45,19 +53,23 @@ pub struct InsertGroup {
     pub group_sorting: i32
 }
 
-#[derive(Queryable, Insertable, AsChangeset, Identifiable)]
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Clone)]
 #[primary_key(channel_id)]
 #[table_name = "bpp_users"]
 pub struct User {
     pub channel_id: String,
     pub display_name: String,
     pub hours_seconds: i64,
-    pub money: f64,
+    pub money: BigDecimal,
     pub first_seen_at: NaiveDateTime,
     pub last_seen_at: NaiveDateTime,
+    /// The rank the user had when their rank was last refreshed, see [`crate::ranks::refresh_ranks`]
+    pub rank_id: Option<i32>,
+    /// When the user was deleted, deleted users are kept until the retention ends so they can be restored
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
-#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations)]
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations, Clone)]
 #[primary_key(group_id, permission)]
 #[table_name = "bpp_groups_permissions"]
 #[belongs_to(Group, foreign_key = "group_id")]
@@ -77,7 +89,7 @@ pub struct GroupUser {
     pub channel_id: String,
 }
 
-#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations)]
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations, Clone)]
 #[primary_key(channel_id, permission)]
 #[table_name = "bpp_users_permissions"]
 #[belongs_to(User, foreign_key = "channel_id")]
@@ -87,6 +99,120 @@ pub struct UserPermission {
     pub granted: bool,
 }
 
+#[derive(Queryable, Identifiable)]
+#[primary_key(transaction_id)]
+#[table_name = "bpp_transactions"]
+pub struct Transaction {
+    pub transaction_id: i64,
+    pub channel_id: String,
+    pub amount: BigDecimal,
+    pub reason: String,
+    pub actor: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "bpp_transactions"]
+pub struct InsertTransaction {
+    pub channel_id: String,
+    pub amount: BigDecimal,
+    pub reason: String,
+    pub actor: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, QueryableByName, Identifiable)]
+#[primary_key(history_id)]
+#[table_name = "bpp_rank_history"]
+pub struct RankHistory {
+    pub history_id: i64,
+    pub channel_id: String,
+    pub old_rank_id: Option<i32>,
+    pub old_rank_name: String,
+    pub new_rank_id: Option<i32>,
+    pub new_rank_name: String,
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Identifiable)]
+#[primary_key(broadcast_id)]
+#[table_name = "bpp_broadcasts"]
+pub struct Broadcast {
+    pub broadcast_id: i32,
+    pub title: String,
+    pub video_id: String,
+    pub started_at: NaiveDateTime,
+    /// Not set while the broadcast is running
+    pub ended_at: Option<NaiveDateTime>,
+    /// Whether the broadcast is or was live or a premiere, see [`crate::sessions::BroadcastState`]
+    pub state: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "bpp_broadcasts"]
+pub struct InsertBroadcast {
+    pub title: String,
+    pub video_id: String,
+    pub started_at: NaiveDateTime,
+    pub state: String,
+}
+
+#[derive(Queryable, Identifiable)]
+#[primary_key(session_id)]
+#[table_name = "bpp_sessions"]
+pub struct Session {
+    pub session_id: i64,
+    pub channel_id: String,
+    /// Not set for sessions outside of a broadcast
+    pub broadcast_id: Option<i32>,
+    pub started_at: NaiveDateTime,
+    /// When the last message of the session was sent
+    pub ended_at: NaiveDateTime,
+    pub message_count: i32,
+    /// How long the user watched during the session
+    pub duration_seconds: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "bpp_sessions"]
+pub struct InsertSession {
+    pub channel_id: String,
+    pub broadcast_id: Option<i32>,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+    pub message_count: i32,
+    pub duration_seconds: i64,
+}
+
+#[derive(Queryable, Identifiable)]
+#[primary_key(audit_id)]
+#[table_name = "bpp_audit_log"]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub actor: String,
+    /// The name of the RPC which made the change
+    pub rpc: String,
+    pub target_type: String,
+    pub target_id: String,
+    /// The target before the change, not set if it didn't exist
+    pub before_value: Option<serde_json::Value>,
+    /// The target after the change, not set if it was removed
+    pub after_value: Option<serde_json::Value>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "bpp_audit_log"]
+pub struct InsertAuditEntry {
+    pub actor: String,
+    pub rpc: String,
+    pub target_type: String,
+    pub target_id: String,
+    pub before_value: Option<serde_json::Value>,
+    pub after_value: Option<serde_json::Value>,
+    pub created_at: NaiveDateTime,
+}
+
 bpp_foreign_model_impl!(
     get_permissions_for_user,
     UserPermission,
@@ -148,27 +274,43 @@ bpp_model_impl!(
     bpp_ranks
 );
 
-impl From<GroupPermission> for String {
-    fn from(gp: GroupPermission) -> String {
-        gp.permission
+pub fn naive_to_timestamp(date_time: &NaiveDateTime) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: date_time.timestamp(),
+        nanos: date_time.timestamp_subsec_nanos() as i32,
     }
 }
 
-pub struct PermissionStrings(Vec<String>);
+/// Converts a timestamp sent by a client, which fails if it lies outside of the range Postgres and chrono both support
+/// or its nanos aren't within a second
+pub fn timestamp_to_naive(timestamp: &prost_types::Timestamp) -> Result<NaiveDateTime, ServiceError> {
+    // The earliest timestamp Postgres stores is 4713 BC, chrono ends before Postgres does at the other end
+    let earliest = NaiveDate::from_ymd(-4712, 11, 24).and_hms(0, 0, 0);
+    let nanos = u32::try_from(timestamp.nanos).ok().filter(|nanos| *nanos < 1_000_000_000);
+    nanos
+        .and_then(|nanos| NaiveDateTime::from_timestamp_opt(timestamp.seconds, nanos))
+        .filter(|date_time| *date_time >= earliest)
+        .ok_or_else(|| ServiceError::InvalidArgument("Timestamp out of range".to_string()))
+}
 
-impl Deref for PermissionStrings {
-    type Target = Vec<String>;
+/// Formats a naive UTC timestamp for JSON documents
+pub fn naive_to_rfc3339(date_time: &NaiveDateTime) -> String {
+    DateTime::<Utc>::from_utc(*date_time, Utc).to_rfc3339()
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+fn to_userservice_permissions<P: PermissionNode>(permissions: &[P]) -> Vec<super::userservice::Permission> {
+    permissions
+        .iter()
+        .map(|p| super::userservice::Permission {
+            permission: p.node().to_string(),
+            granted: p.granted(),
+        })
+        .collect()
 }
 
-impl From<Vec<GroupPermission>> for PermissionStrings {
-    fn from(permissions: Vec<GroupPermission>) -> Self {
-        let permissions: Vec<String> =
-            permissions.into_iter().map(|gp| gp.permission).collect();
-        PermissionStrings(permissions)
+impl From<GroupPermission> for String {
+    fn from(gp: GroupPermission) -> String {
+        gp.permission
     }
 }
 
@@ -178,14 +320,6 @@ impl From<UserPermission> for String {
     }
 }
 
-impl From<Vec<UserPermission>> for PermissionStrings {
-    fn from(permissions: Vec<UserPermission>) -> Self {
-        let permissions: Vec<String> =
-            permissions.into_iter().map(|up| up.permission).collect();
-        PermissionStrings(permissions)
-    }
-}
-
 // impl PartialEq for Group {
 //     fn eq(&self, other: &Self) -> bool {
 //         self.group_id == other.group_id
@@ -193,7 +327,7 @@ impl From<Vec<UserPermission>> for PermissionStrings {
 // }
 impl PartialOrd for Group {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.group_sorting.partial_cmp(&other.group_sorting)
+        Some(self.cmp(other))
     }
 }
 
@@ -208,7 +342,7 @@ impl User {
         channel_id: String,
         display_name: String,
         hours_seconds: i64,
-        money: f64,
+        money: BigDecimal,
         first_seen_at: NaiveDateTime,
         last_seen_at: NaiveDateTime,
     ) -> User {
@@ -219,48 +353,251 @@ impl User {
             money,
             first_seen_at,
             last_seen_at,
+            rank_id: None,
+            deleted_at: None,
         }
     }
 
-    pub fn check_if_exists(check_channel_id: &str, conn: &diesel::PgConnection) -> bool {
+    pub fn check_if_exists(check_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<bool> {
         use super::schema::bpp_users::dsl::*;
         use diesel::dsl::exists;
         use diesel::select;
-        let exists: bool = select(exists(bpp_users.filter(channel_id.eq(check_channel_id))))
+        select(exists(bpp_users.filter(channel_id.eq(check_channel_id)))).get_result(conn)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Loads a user unless they were deleted
+    pub fn get_existing(check_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<User> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.eq(check_channel_id))
+            .filter(deleted_at.is_null())
+            .first::<User>(conn)
+    }
+
+    /// Loads a user and locks their row until the end of the current transaction, deleted users are loaded as well
+    pub fn get_for_update(check_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<Option<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.eq(check_channel_id))
+            .for_update()
+            .first::<User>(conn)
+            .optional()
+    }
+
+    /// Loads the users and locks their rows until the end of the current transaction.
+    ///
+    /// The rows are locked ordered by channel id, so concurrent callers can't deadlock each other. Deleted users are
+    /// loaded as well.
+    pub fn get_many_for_update(channel_ids: &[&str], conn: &diesel::PgConnection) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.eq_any(channel_ids))
+            .order(channel_id.asc())
+            .for_update()
+            .load::<User>(conn)
+    }
+
+    /// Adds an amount to the money of a user in the database, without reading it first
+    pub fn add_money(check_channel_id: &str, amount: &BigDecimal, conn: &diesel::PgConnection) -> QueryResult<User> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(channel_id.eq(check_channel_id)))
+            .set(money.eq(money + amount.clone()))
+            .get_result(conn)
+    }
+
+    /// Adds a possibly negative amount to the money of a user in the database, optionally not going below zero
+    pub fn adjust_money(
+        check_channel_id: &str,
+        delta: &BigDecimal,
+        floor_at_zero: bool,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<User> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::Numeric;
+        if !floor_at_zero {
+            return User::add_money(check_channel_id, delta, conn);
+        }
+
+        diesel::update(bpp_users.filter(channel_id.eq(check_channel_id)))
+            .set(money.eq(sql::<Numeric>("GREATEST(money + ").bind::<Numeric, _>(delta.clone()).sql(", 0)")))
+            .get_result(conn)
+    }
+
+    /// Adds a possibly negative number of seconds to the hours of a user in the database, optionally not going below
+    /// zero
+    pub fn adjust_hours(
+        check_channel_id: &str,
+        delta_seconds: i64,
+        floor_at_zero: bool,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<User> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::BigInt;
+        let target = bpp_users.filter(channel_id.eq(check_channel_id));
+        if floor_at_zero {
+            diesel::update(target)
+                .set(hours_seconds.eq(sql::<BigInt>("GREATEST(hours_seconds + ").bind::<BigInt, _>(delta_seconds).sql(", 0)")))
+                .get_result(conn)
+        } else {
+            diesel::update(target)
+                .set(hours_seconds.eq(hours_seconds + delta_seconds))
+                .get_result(conn)
+        }
+    }
+
+    /// Returns the channel ids which don't belong to any user, deleted users count as missing
+    pub fn find_missing(channel_ids: &[String], conn: &diesel::PgConnection) -> QueryResult<Vec<String>> {
+        use super::schema::bpp_users::dsl::*;
+        let existing: Vec<String> = bpp_users
+            .filter(channel_id.eq_any(channel_ids))
+            .filter(deleted_at.is_null())
+            .select(channel_id)
+            .load(conn)?;
+        Ok(channel_ids
+            .iter()
+            .filter(|id| !existing.contains(id))
+            .cloned()
+            .collect())
+    }
+
+    /// Marks the users as deleted and returns the users which weren't deleted yet. They are kept, together with
+    /// everything belonging to them, until [`User::purge_deleted`] removes them.
+    pub fn soft_delete(
+        channel_ids: &[String],
+        now: NaiveDateTime,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(channel_id.eq_any(channel_ids)).filter(deleted_at.is_null()))
+            .set(deleted_at.eq(now))
+            .get_results(conn)
+    }
+
+    /// Undoes the deletion of a user, fails with `NotFound` if there is no deleted user with this channel id
+    pub fn restore(check_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<User> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(channel_id.eq(check_channel_id)).filter(deleted_at.is_not_null()))
+            .set(deleted_at.eq(None::<NaiveDateTime>))
             .get_result(conn)
-            .unwrap();
-        exists
     }
 
-    pub fn get_active_rank(&self, conn: &diesel::PgConnection) -> Option<Rank> {
+    pub fn count_deleted(conn: &diesel::PgConnection) -> QueryResult<i64> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users.filter(deleted_at.is_not_null()).count().get_result(conn)
+    }
+
+    /// Loads one page of the deleted users, the most recently deleted first
+    pub fn get_deleted(page: &Page, conn: &diesel::PgConnection) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(deleted_at.is_not_null())
+            .order((deleted_at.desc(), channel_id.asc()))
+            .limit(page.limit)
+            .offset(page.offset)
+            .load::<User>(conn)
+    }
+
+    /// Deletes the users which were deleted before `deleted_before` for good and returns their channel ids
+    pub fn purge_deleted(deleted_before: NaiveDateTime, conn: &diesel::PgConnection) -> QueryResult<Vec<String>> {
+        use super::schema::bpp_users::dsl::*;
+        conn.transaction(|| {
+            // Lock the users, so they can't be restored while they are purged
+            let purged: Vec<String> = bpp_users
+                .filter(deleted_at.lt(deleted_before))
+                .select(channel_id)
+                .order(channel_id.asc())
+                .for_update()
+                .load(conn)?;
+            if !purged.is_empty() {
+                User::delete_many(&purged, conn)?;
+            }
+            Ok(purged)
+        })
+    }
+
+    /// Deletes the users together with their memberships, permissions, rank history and sessions, and returns the
+    /// memberships they had.
+    ///
+    /// The ledger is append-only, so the transactions of each user are kept and moved to a random channel id instead.
+    /// The channel could come back as a new user, who mustn't inherit the old transactions. The audit log refers to
+    /// that channel id as well and loses the display names, like for erased users.
+    pub fn delete_many(channel_ids: &[String], conn: &diesel::PgConnection) -> QueryResult<Vec<GroupUser>> {
+        conn.transaction(|| {
+            let memberships = diesel::delete(bpp_groups_users::table.filter(bpp_groups_users::channel_id.eq_any(channel_ids)))
+                .get_results(conn)?;
+            diesel::delete(bpp_users_permissions::table.filter(bpp_users_permissions::channel_id.eq_any(channel_ids)))
+                .execute(conn)?;
+            for deleted_channel_id in channel_ids {
+                let pseudonym = format!("purged-{:016x}", rand::thread_rng().gen::<u64>());
+                diesel::update(bpp_transactions::table.filter(bpp_transactions::channel_id.eq(deleted_channel_id)))
+                    .set(bpp_transactions::channel_id.eq(&pseudonym))
+                    .execute(conn)?;
+                diesel::update(bpp_transactions::table.filter(bpp_transactions::actor.eq(deleted_channel_id)))
+                    .set(bpp_transactions::actor.eq(&pseudonym))
+                    .execute(conn)?;
+                audit::pseudonymise_user(deleted_channel_id, &pseudonym, conn)?;
+            }
+            diesel::delete(bpp_rank_history::table.filter(bpp_rank_history::channel_id.eq_any(channel_ids)))
+                .execute(conn)?;
+            diesel::delete(bpp_sessions::table.filter(bpp_sessions::channel_id.eq_any(channel_ids)))
+                .execute(conn)?;
+            diesel::delete(bpp_users::table.filter(bpp_users::channel_id.eq_any(channel_ids))).execute(conn)?;
+            Ok(memberships)
+        })
+    }
+
+    pub fn get_active_rank(&self, conn: &diesel::PgConnection) -> QueryResult<Option<Rank>> {
         use super::schema::bpp_ranks::dsl::*;
 
         // Get all ranks which match the hour requirements and sort by the sorting field
-        let rank: Option<Rank> = bpp_ranks
+        bpp_ranks
             .filter(hour_requirement_seconds.le(self.hours_seconds))
             .order(rank_sorting.desc())
-            .first::<Rank>(conn).ok();
-        rank
+            .first::<Rank>(conn)
+            .optional()
     }
 
-    pub fn to_userservice_user(&self, conn: &diesel::PgConnection) -> BppUser {
-        let prost_duration = prost_types::Duration {
-            seconds: self.hours_seconds,
-            nanos: 0,
-        };
+    /// Returns the name of the active rank or `default` if the user hasn't reached any rank yet
+    pub fn get_active_rank_name(&self, conn: &diesel::PgConnection) -> QueryResult<String> {
+        if let Some(rank) = self.get_active_rank(conn)? {
+            Ok(rank.rank_name)
+        } else {
+            Ok("default".to_string())
+        }
+    }
 
-        let first_seen_at_ts = prost_types::Timestamp {
-            seconds: self.first_seen_at.timestamp() as i64,
-            nanos: self.first_seen_at.timestamp_subsec_nanos() as i32,
-        };
-        let last_seen_at_ts = prost_types::Timestamp {
-            seconds: self.last_seen_at.timestamp() as i64,
-            nanos: self.last_seen_at.timestamp_subsec_nanos() as i32,
-        };
+    /// Returns the names of the fields which differ from the other user, as they are called in the API
+    pub fn changed_fields(&self, other: &User) -> Vec<String> {
+        let mut changed_fields = Vec::new();
+        if self.display_name != other.display_name {
+            changed_fields.push("display_name".to_string());
+        }
+        if self.hours_seconds != other.hours_seconds {
+            changed_fields.push("hours".to_string());
+        }
+        if self.money != other.money {
+            changed_fields.push("money".to_string());
+            changed_fields.push("money_minor_units".to_string());
+        }
+        if self.first_seen_at != other.first_seen_at {
+            changed_fields.push("first_seen_at".to_string());
+        }
+        if self.last_seen_at != other.last_seen_at {
+            changed_fields.push("last_seen_at".to_string());
+        }
+        changed_fields
+    }
 
-        let groups = Group::get_groups_for_user(self.channel_id.clone(), conn);
+    pub fn to_userservice_user(&self, conn: &diesel::PgConnection) -> QueryResult<BppUser> {
+        let groups = Group::get_groups_for_user(self.channel_id.clone(), conn)?;
         let permissions =
-            UserPermission::get_permissions_for_user(self.channel_id.clone(), conn);
+            UserPermission::get_permissions_for_user(self.channel_id.clone(), conn)?;
         let permissions: Vec<super::userservice::Permission> = permissions.into_iter()
             .map(|p|super::userservice::Permission {
                 permission: p.permission,
@@ -271,7 +608,7 @@ impl User {
             .iter()
             .map(|group| {
                 let permissions =
-                    GroupPermission::get_permissions_for_group(group.group_id, conn);
+                    GroupPermission::get_permissions_for_group(group.group_id, conn)?;
                 let permissions = permissions.into_iter()
                     .map(|p|super::userservice::Permission {
                         permission: p.permission,
@@ -279,70 +616,221 @@ impl User {
                     })
                     .collect();
 
-                super::userservice::BppGroup {
+                Ok(super::userservice::BppGroup {
                     group_id: group.group_id,
                     group_name: group.group_name.clone(),
                     permissions,
                     bonus_payout: group.bonus_payout,
                     group_sorting: group.group_sorting,
-                }
+                })
             })
-            .collect::<Vec<super::userservice::BppGroup>>();
+            .collect::<QueryResult<Vec<super::userservice::BppGroup>>>()?;
 
-        let rank = if let Some(rank) = self.get_active_rank(conn) {
-            rank.rank_name
-        } else {
-            "default".to_string()
+        let rank = self.get_active_rank_name(conn)?;
+
+        Ok(self.build_userservice_user(groups, permissions, rank))
+    }
+
+    /// Converts many users at once, taking groups and ranks from the cache and loading the memberships and permissions
+    /// of all of them in two queries instead of querying them per user like [`User::to_userservice_user`]
+    pub fn to_userservice_users(
+        users: &[User],
+        snapshot: &Snapshot,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<BppUser>> {
+        let channel_ids: Vec<&str> = users.iter().map(|user| user.channel_id.as_str()).collect();
+        let grants = UserGrants::load(&channel_ids, conn)?;
+        let users = users
+            .iter()
+            .map(|user| {
+                let groups = snapshot
+                    .groups_for_user(&grants, &user.channel_id)
+                    .into_iter()
+                    .map(|group| BppGroup {
+                        group_id: group.group_id,
+                        group_name: group.group_name.clone(),
+                        permissions: to_userservice_permissions(snapshot.permissions_for_group(group.group_id)),
+                        bonus_payout: group.bonus_payout,
+                        group_sorting: group.group_sorting,
+                    })
+                    .collect();
+                let permissions = to_userservice_permissions(grants.permissions_for_user(&user.channel_id));
+                let rank = snapshot
+                    .active_rank(user.hours_seconds)
+                    .map(|rank| rank.rank_name.clone())
+                    .unwrap_or_else(|| "default".to_string());
+                user.build_userservice_user(groups, permissions, rank)
+            })
+            .collect();
+        Ok(users)
+    }
+
+    // Still fills the deprecated money field for clients which predate money_minor_units
+    #[allow(deprecated)]
+    fn build_userservice_user(
+        &self,
+        groups: Vec<BppGroup>,
+        permissions: Vec<super::userservice::Permission>,
+        rank: String,
+    ) -> BppUser {
+        let prost_duration = prost_types::Duration {
+            seconds: self.hours_seconds,
+            nanos: 0,
+        };
+
+        let first_seen_at_ts = prost_types::Timestamp {
+            seconds: self.first_seen_at.timestamp(),
+            nanos: self.first_seen_at.timestamp_subsec_nanos() as i32,
+        };
+        let last_seen_at_ts = prost_types::Timestamp {
+            seconds: self.last_seen_at.timestamp(),
+            nanos: self.last_seen_at.timestamp_subsec_nanos() as i32,
         };
 
         BppUser {
             channel_id: self.channel_id.clone(),
             display_name: self.display_name.clone(),
             hours: Some(prost_duration),
-            money: self.money,
+            money: money::to_units_float(&self.money),
+            money_minor_units: money::to_minor_units(&self.money),
             first_seen_at: Some(first_seen_at_ts),
             last_seen_at: Some(last_seen_at_ts),
             groups,
             permissions,
-            rank
+            rank,
+            deleted_at: self.deleted_at.as_ref().map(naive_to_timestamp),
         }
     }
 }
 
-impl From<CreateBppRank> for InsertRank {
-    fn from(rank: CreateBppRank) -> InsertRank {
-        let requirement = rank.hour_requirement.unwrap();
-        InsertRank {
-            rank_name: rank.rank_name,
-            hour_requirement_seconds: requirement.seconds,
-            hour_requirement_nanos: requirement.nanos,
-            rank_sorting: rank.rank_sorting,
-        }
+impl Group {
+    /// Loads the groups and locks their rows until the end of the current transaction, which also keeps members from
+    /// being added to them
+    pub fn get_many_for_update(group_ids: &[i32], conn: &diesel::PgConnection) -> QueryResult<Vec<Group>> {
+        bpp_groups::table
+            .filter(bpp_groups::group_id.eq_any(group_ids))
+            .order(bpp_groups::group_id.asc())
+            .for_update()
+            .load::<Group>(conn)
+    }
+
+    /// Deletes the groups together with their memberships and permissions, and returns the memberships they had
+    pub fn delete_many(group_ids: &[i32], conn: &diesel::PgConnection) -> QueryResult<Vec<GroupUser>> {
+        conn.transaction(|| {
+            let memberships = diesel::delete(bpp_groups_users::table.filter(bpp_groups_users::group_id.eq_any(group_ids)))
+                .get_results(conn)?;
+            diesel::delete(bpp_groups_permissions::table.filter(bpp_groups_permissions::group_id.eq_any(group_ids)))
+                .execute(conn)?;
+            diesel::delete(bpp_groups::table.filter(bpp_groups::group_id.eq_any(group_ids))).execute(conn)?;
+            Ok(memberships)
+        })
     }
 }
 
-impl From<BppRank> for Rank {
-    fn from(br: BppRank) -> Self {
-        let requirement = br.hour_requirement.unwrap();
-        Rank {
-            rank_id: br.rank_id,
-            rank_name: br.rank_name,
+impl GroupUser {
+    /// Adds the users to the group and returns the channel ids of the new members, users which already are members
+    /// are skipped
+    pub fn add_users(check_group_id: i32, channel_ids: &[String], conn: &diesel::PgConnection) -> QueryResult<Vec<String>> {
+        use super::schema::bpp_groups_users::dsl::*;
+        let memberships: Vec<GroupUser> = channel_ids
+            .iter()
+            .map(|id| GroupUser {
+                group_id: check_group_id,
+                channel_id: id.clone(),
+            })
+            .collect();
+        diesel::insert_into(bpp_groups_users)
+            .values(&memberships)
+            .on_conflict_do_nothing()
+            .returning(channel_id)
+            .get_results(conn)
+    }
+
+    /// Removes the users from the group and returns the channel ids of the users which were members
+    pub fn remove_users(check_group_id: i32, channel_ids: &[String], conn: &diesel::PgConnection) -> QueryResult<Vec<String>> {
+        use super::schema::bpp_groups_users::dsl::*;
+        diesel::delete(
+            bpp_groups_users
+                .filter(group_id.eq(check_group_id))
+                .filter(channel_id.eq_any(channel_ids)),
+        )
+        .returning(channel_id)
+        .get_results(conn)
+    }
+
+    /// Returns which of the groups have at least one member, deleted users don't count
+    pub fn groups_with_members(group_ids: &[i32], conn: &diesel::PgConnection) -> QueryResult<Vec<i32>> {
+        use super::schema::bpp_groups_users::dsl::*;
+        use super::schema::bpp_users;
+        bpp_groups_users
+            .filter(group_id.eq_any(group_ids))
+            .inner_join(bpp_users::table)
+            .filter(bpp_users::deleted_at.is_null())
+            .select(group_id)
+            .distinct()
+            .order(group_id.asc())
+            .load(conn)
+    }
+
+    /// Counts the members of a group, deleted users don't count
+    pub fn count_members(check_group_id: i32, conn: &diesel::PgConnection) -> QueryResult<i64> {
+        use super::schema::bpp_groups_users::dsl::*;
+        use super::schema::bpp_users;
+        bpp_groups_users
+            .filter(group_id.eq(check_group_id))
+            .inner_join(bpp_users::table)
+            .filter(bpp_users::deleted_at.is_null())
+            .count()
+            .get_result(conn)
+    }
+
+    /// Loads one page of the members of a group which weren't deleted, ordered by their channel id
+    pub fn get_members(check_group_id: i32, page: &Page, conn: &diesel::PgConnection) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_groups_users::dsl::*;
+        use super::schema::bpp_users;
+        bpp_groups_users
+            .filter(group_id.eq(check_group_id))
+            .inner_join(bpp_users::table)
+            .filter(bpp_users::deleted_at.is_null())
+            .select(bpp_users::all_columns)
+            .order(bpp_users::channel_id.asc())
+            .limit(page.limit)
+            .offset(page.offset)
+            .load::<User>(conn)
+    }
+}
+
+/// Reads the hour requirement of a rank sent by a client, which has to be set
+fn hour_requirement(requirement: Option<&Duration>) -> Result<&Duration, ServiceError> {
+    requirement.ok_or_else(|| ServiceError::InvalidArgument("Hour requirement must be set".to_string()))
+}
+
+impl TryFrom<CreateBppRank> for InsertRank {
+    type Error = ServiceError;
+
+    fn try_from(rank: CreateBppRank) -> Result<InsertRank, ServiceError> {
+        let requirement = hour_requirement(rank.hour_requirement.as_ref())?;
+        Ok(InsertRank {
+            rank_name: rank.rank_name,
             hour_requirement_seconds: requirement.seconds,
             hour_requirement_nanos: requirement.nanos,
-            rank_sorting: br.rank_sorting,
-        }
+            rank_sorting: rank.rank_sorting,
+        })
     }
 }
 
-impl From<&BppRank> for Rank {
-    fn from(br: &BppRank) -> Self {
-        Rank {
+impl TryFrom<&BppRank> for Rank {
+    type Error = ServiceError;
+
+    fn try_from(br: &BppRank) -> Result<Rank, ServiceError> {
+        let requirement = hour_requirement(br.hour_requirement.as_ref())?;
+        Ok(Rank {
             rank_id: br.rank_id,
             rank_name: br.rank_name.clone(),
-            hour_requirement_seconds: br.hour_requirement.as_ref().unwrap().seconds,
-            hour_requirement_nanos: br.hour_requirement.as_ref().unwrap().nanos,
+            hour_requirement_seconds: requirement.seconds,
+            hour_requirement_nanos: requirement.nanos,
             rank_sorting: br.rank_sorting,
-        }
+        })
     }
 }
 
@@ -378,50 +866,44 @@ impl From<&BppGroup> for Group {
     }
 }
 
-impl From<BppUser> for User {
-    fn from(user: BppUser) -> User {
-        let hours = user.hours.unwrap_or(Duration {
-            seconds: 0,
-            nanos: 0,
-        });
-        let first_seen_at = user.first_seen_at.unwrap();
-        let last_seen_at = user.last_seen_at.unwrap();
-
-        let first_seen_at_naive =
-            NaiveDateTime::from_timestamp(first_seen_at.seconds, first_seen_at.nanos as u32);
-        let last_seen_at_naive =
-            NaiveDateTime::from_timestamp(last_seen_at.seconds, last_seen_at.nanos as u32);
-        User {
-            channel_id: user.channel_id,
-            display_name: user.display_name,
-            hours_seconds: hours.seconds,
-            money: user.money,
-            first_seen_at: first_seen_at_naive,
-            last_seen_at: last_seen_at_naive,
-        }
-    }
-}
+impl TryFrom<&BppUser> for User {
+    type Error = ServiceError;
 
-impl From<&BppUser> for User {
-    fn from(user: &BppUser) -> User {
+    fn try_from(user: &BppUser) -> Result<User, ServiceError> {
         let hours = user.hours.clone().unwrap_or(Duration {
             seconds: 0,
             nanos: 0,
         });
-        let first_seen_at = user.first_seen_at.clone().unwrap();
-        let last_seen_at = user.last_seen_at.clone().unwrap();
+        let (first_seen_at, last_seen_at) = match (&user.first_seen_at, &user.last_seen_at) {
+            (Some(first_seen_at), Some(last_seen_at)) => (first_seen_at, last_seen_at),
+            _ => {
+                return Err(ServiceError::InvalidArgument(
+                    "First and last seen timestamps must be set".to_string(),
+                ))
+            }
+        };
 
-        let first_seen_at_naive =
-            NaiveDateTime::from_timestamp(first_seen_at.seconds, first_seen_at.nanos as u32);
-        let last_seen_at_naive =
-            NaiveDateTime::from_timestamp(last_seen_at.seconds, last_seen_at.nanos as u32);
-        User {
+        // Clients which predate money_minor_units only send the deprecated money field
+        #[allow(deprecated)]
+        let minor_units = match (user.money_minor_units, user.money) {
+            (0, units) if units != 0.0 => money::units_float_to_minor_units(units),
+            (minor_units, _) => Some(minor_units),
+        };
+        let minor_units = minor_units
+            .filter(|minor_units| (-money::MAX_MINOR_UNITS..=money::MAX_MINOR_UNITS).contains(minor_units))
+            .ok_or_else(|| ServiceError::InvalidArgument("Money exceeds the supported range".to_string()))?;
+
+        let first_seen_at_naive = timestamp_to_naive(first_seen_at)?;
+        let last_seen_at_naive = timestamp_to_naive(last_seen_at)?;
+        Ok(User {
             channel_id: user.channel_id.clone(),
             display_name: user.display_name.clone(),
             hours_seconds: hours.seconds,
-            money: user.money,
+            money: money::from_minor_units(minor_units),
             first_seen_at: first_seen_at_naive,
             last_seen_at: last_seen_at_naive,
-        }
+            rank_id: None,
+            deleted_at: None,
+        })
     }
 }
@@ -0,0 +1,119 @@
+use bigdecimal::{BigDecimal, Signed, ToPrimitive};
+
+/// The number of decimal places money is stored with
+pub const MONEY_SCALE: i64 = 2;
+/// The number of minor units which make up one unit of money
+pub const MINOR_UNITS_PER_UNIT: i64 = 100;
+
+/// The most minor units an amount stored in the NUMERIC(18, 2) money columns can have, in either direction
+pub const MAX_MINOR_UNITS: i64 = 999_999_999_999_999_999;
+
+/// Converts minor units, as used by the API, to an amount of money
+pub fn from_minor_units(minor_units: i64) -> BigDecimal {
+    BigDecimal::new(minor_units.into(), MONEY_SCALE)
+}
+
+/// Converts an amount of money to minor units, as used by the API.
+///
+/// Digits beyond the minor units are dropped, which never happens for amounts loaded from the database. Stored amounts
+/// and the differences between two of them always fit into an i64, as the columns are NUMERIC(18, 2). Larger amounts,
+/// like sums of many transactions, are clamped to its range.
+pub fn to_minor_units(amount: &BigDecimal) -> i64 {
+    let (minor_units, _) = amount.with_scale(MONEY_SCALE).into_bigint_and_exponent();
+    minor_units.to_i64().unwrap_or(if minor_units.is_negative() { i64::MIN } else { i64::MAX })
+}
+
+/// Whether an amount fits into the money columns, balances have to be checked before they are stored
+pub fn is_storable(amount: &BigDecimal) -> bool {
+    amount.abs() <= from_minor_units(MAX_MINOR_UNITS)
+}
+
+/// Converts an amount of money to units as a float, for the deprecated money fields of the API which predate minor
+/// units. Not every amount can be represented exactly.
+pub fn to_units_float(amount: &BigDecimal) -> f64 {
+    amount.to_f64().unwrap_or(0.0)
+}
+
+/// Converts units sent as a float through the deprecated money fields of the API to minor units, rounded to the
+/// nearest minor unit. Fails for amounts which aren't finite or don't fit into the money columns.
+pub fn units_float_to_minor_units(units: f64) -> Option<i64> {
+    if !units.is_finite() {
+        return None;
+    }
+    // Casting saturates, so amounts far beyond the range end up at its bounds and fail the check below
+    let minor_units = (units * MINOR_UNITS_PER_UNIT as f64).round() as i64;
+    Some(minor_units).filter(|minor_units| (-MAX_MINOR_UNITS..=MAX_MINOR_UNITS).contains(minor_units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn amount(text: &str) -> BigDecimal {
+        BigDecimal::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn minor_units_convert_to_amounts() {
+        assert_eq!(from_minor_units(12345), amount("123.45"));
+        assert_eq!(from_minor_units(-5), amount("-0.05"));
+        assert_eq!(from_minor_units(0), amount("0"));
+    }
+
+    #[test]
+    fn amounts_convert_to_minor_units() {
+        assert_eq!(to_minor_units(&amount("123.45")), 12345);
+        assert_eq!(to_minor_units(&amount("-0.05")), -5);
+        assert_eq!(to_minor_units(&amount("7")), 700);
+    }
+
+    #[test]
+    fn digits_beyond_minor_units_are_dropped() {
+        assert_eq!(to_minor_units(&amount("1.239")), 123);
+        assert_eq!(to_minor_units(&amount("-1.239")), -123);
+    }
+
+    #[test]
+    fn amounts_beyond_i64_are_clamped() {
+        assert_eq!(to_minor_units(&amount("100000000000000000000")), i64::MAX);
+        assert_eq!(to_minor_units(&amount("-100000000000000000000")), i64::MIN);
+    }
+
+    #[test]
+    fn the_largest_amounts_are_storable() {
+        assert!(is_storable(&from_minor_units(MAX_MINOR_UNITS)));
+        assert!(is_storable(&from_minor_units(-MAX_MINOR_UNITS)));
+        assert!(!is_storable(&(from_minor_units(MAX_MINOR_UNITS) + from_minor_units(1))));
+        assert!(!is_storable(&(from_minor_units(-MAX_MINOR_UNITS) - from_minor_units(1))));
+    }
+
+    #[test]
+    fn the_largest_amounts_round_trip() {
+        assert_eq!(to_minor_units(&from_minor_units(MAX_MINOR_UNITS)), MAX_MINOR_UNITS);
+        assert_eq!(to_minor_units(&from_minor_units(-MAX_MINOR_UNITS)), -MAX_MINOR_UNITS);
+    }
+
+    #[test]
+    fn floats_round_to_the_nearest_minor_unit() {
+        assert_eq!(units_float_to_minor_units(12.344), Some(1234));
+        assert_eq!(units_float_to_minor_units(12.346), Some(1235));
+        assert_eq!(units_float_to_minor_units(-0.016), Some(-2));
+        assert_eq!(units_float_to_minor_units(0.0), Some(0));
+    }
+
+    #[test]
+    fn floats_outside_of_the_stored_range_are_rejected() {
+        assert_eq!(units_float_to_minor_units(f64::NAN), None);
+        assert_eq!(units_float_to_minor_units(f64::INFINITY), None);
+        assert_eq!(units_float_to_minor_units(1e17), None);
+        assert_eq!(units_float_to_minor_units(-1e17), None);
+        assert_eq!(units_float_to_minor_units(1e15), Some(100_000_000_000_000_000));
+    }
+
+    #[test]
+    fn amounts_convert_to_floats() {
+        assert_eq!(to_units_float(&amount("123.45")), 123.45);
+        assert_eq!(to_units_float(&amount("-0.5")), -0.5);
+    }
+}
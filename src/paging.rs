@@ -0,0 +1,49 @@
+use crate::error::ServiceError;
+
+/// The page size used when a request doesn't specify one
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+/// The largest page size a request may ask for
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Offset based paging, where the page token is the offset of the first entry of the page
+pub struct Page {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl Page {
+    /// Creates a page from the page size and page token of a request
+    pub fn new(page_size: i32, page_token: &str) -> Result<Page, ServiceError> {
+        Page::with_default_size(page_size, page_token, DEFAULT_PAGE_SIZE)
+    }
+
+    /// Creates a page like [`Page::new`], but uses `default_size` when the request doesn't specify a page size
+    pub fn with_default_size(page_size: i32, page_token: &str, default_size: i64) -> Result<Page, ServiceError> {
+        let limit = match page_size {
+            size if size < 0 => return Err(ServiceError::InvalidArgument("Page size must not be negative".to_string())),
+            0 => default_size,
+            size => (size as i64).min(MAX_PAGE_SIZE),
+        };
+
+        let offset = if page_token.is_empty() {
+            0
+        } else {
+            match page_token.parse::<i64>() {
+                Ok(offset) if offset >= 0 => offset,
+                _ => return Err(ServiceError::InvalidArgument("Invalid page token".to_string())),
+            }
+        };
+
+        Ok(Page { limit, offset })
+    }
+
+    /// Returns the token of the following page or an empty string if this is the last page
+    pub fn next_page_token(&self, total: i64) -> String {
+        let next_offset = self.offset + self.limit;
+        if next_offset < total {
+            next_offset.to_string()
+        } else {
+            String::new()
+        }
+    }
+}
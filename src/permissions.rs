@@ -0,0 +1,332 @@
+use crate::cache::{Snapshot, UserGrants};
+use crate::models::{Group, GroupPermission, UserPermission};
+use crate::userservice::permission_decision::Source;
+use crate::userservice::{Permission, PermissionDecision};
+
+/// Separates the segments of a permission node, e.g. `chat.command.songrequest`
+const NODE_SEPARATOR: char = '.';
+/// A node consisting only of this segment matches every permission
+const WILDCARD: &str = "*";
+
+/// A granted or revoked permission node, either of a group or of a user
+pub trait PermissionNode {
+    fn node(&self) -> &str;
+    fn granted(&self) -> bool;
+}
+
+impl PermissionNode for GroupPermission {
+    fn node(&self) -> &str {
+        &self.permission
+    }
+
+    fn granted(&self) -> bool {
+        self.granted
+    }
+}
+
+impl PermissionNode for UserPermission {
+    fn node(&self) -> &str {
+        &self.permission
+    }
+
+    fn granted(&self) -> bool {
+        self.granted
+    }
+}
+
+/// Returns how specific `node` is for `permission` or `None` if it doesn't match at all.
+///
+/// An exact match is always more specific than a wildcard and `chat.command.*` is more specific than `chat.*`,
+/// which in turn is more specific than `*`.
+pub fn node_specificity(node: &str, permission: &str) -> Option<usize> {
+    if node == permission {
+        return Some(permission.split(NODE_SEPARATOR).count());
+    }
+    if node == WILDCARD {
+        return Some(0);
+    }
+
+    let prefix = node.strip_suffix(WILDCARD)?.strip_suffix(NODE_SEPARATOR)?;
+    let remainder = permission.strip_prefix(prefix)?;
+    if remainder.starts_with(NODE_SEPARATOR) {
+        Some(prefix.split(NODE_SEPARATOR).count())
+    } else {
+        None
+    }
+}
+
+/// Finds the entry which matches the permission most specifically
+pub fn most_specific_node<'a, P: PermissionNode>(entries: &'a [P], permission: &str) -> Option<&'a P> {
+    entries
+        .iter()
+        .filter_map(|entry| node_specificity(entry.node(), permission).map(|specificity| (specificity, entry)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, entry)| entry)
+}
+
+/// Where a step of a permission check took its decision from
+pub enum DecisionSource {
+    Default,
+    Group {
+        group_id: i32,
+        group_name: String,
+        group_sorting: i32,
+    },
+    User,
+}
+
+/// The node which matched during a step of a permission check
+pub struct MatchedNode {
+    pub node: String,
+    pub granted: bool,
+}
+
+/// A single step of a permission check and the verdict after it
+pub struct DecisionStep {
+    pub source: DecisionSource,
+    pub matched: Option<MatchedNode>,
+    pub verdict: bool,
+}
+
+/// The ordered steps which led to the result of a permission check
+pub struct PermissionTrace {
+    pub steps: Vec<DecisionStep>,
+    pub granted: bool,
+}
+
+fn decide<P: PermissionNode>(source: DecisionSource, entries: &[P], permission: &str, verdict: &mut bool) -> DecisionStep {
+    let matched = most_specific_node(entries, permission).map(|entry| MatchedNode {
+        node: entry.node().to_string(),
+        granted: entry.granted(),
+    });
+    if let Some(matched) = &matched {
+        *verdict = matched.granted;
+    }
+
+    DecisionStep {
+        source,
+        matched,
+        verdict: *verdict,
+    }
+}
+
+/// Checks a permission and records every step of the check.
+///
+/// Starting with the default, the groups are consulted by ascending group sorting, so higher sorted groups override
+/// lower sorted ones, and finally the permissions of the user itself override all groups. Within each of these, only
+/// the most specific matching node is considered.
+pub fn explain_permission(
+    permission: &str,
+    granted_default: bool,
+    groups: &[(Group, Vec<GroupPermission>)],
+    user_permissions: &[UserPermission],
+) -> PermissionTrace {
+    let mut verdict = granted_default;
+    let mut steps = vec![DecisionStep {
+        source: DecisionSource::Default,
+        matched: None,
+        verdict,
+    }];
+
+    let mut sorted_groups: Vec<&(Group, Vec<GroupPermission>)> = groups.iter().collect();
+    sorted_groups.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (group, group_permissions) in sorted_groups {
+        let source = DecisionSource::Group {
+            group_id: group.group_id,
+            group_name: group.group_name.clone(),
+            group_sorting: group.group_sorting,
+        };
+        steps.push(decide(source, group_permissions, permission, &mut verdict));
+    }
+
+    steps.push(decide(DecisionSource::User, user_permissions, permission, &mut verdict));
+
+    PermissionTrace {
+        steps,
+        granted: verdict,
+    }
+}
+
+/// Resolves whether a permission is granted, see [`explain_permission`] for the rules
+pub fn resolve_permission(
+    permission: &str,
+    granted_default: bool,
+    groups: &[(Group, Vec<GroupPermission>)],
+    user_permissions: &[UserPermission],
+) -> bool {
+    explain_permission(permission, granted_default, groups, user_permissions).granted
+}
+
+/// Collects the groups with their permissions and the permissions of a user
+fn permission_sources(
+    snapshot: &Snapshot,
+    grants: &UserGrants,
+    channel_id: &str,
+) -> (Vec<(Group, Vec<GroupPermission>)>, Vec<UserPermission>) {
+    let groups: Vec<(Group, Vec<GroupPermission>)> = snapshot
+        .groups_for_user(grants, channel_id)
+        .into_iter()
+        .map(|group| (group.clone(), snapshot.permissions_for_group(group.group_id).to_vec()))
+        .collect();
+    let user_permissions = grants.permissions_for_user(channel_id).to_vec();
+
+    (groups, user_permissions)
+}
+
+/// Resolves the permission of a user with the groups of the cache and the memberships and permissions of the user
+pub fn user_has_permission(
+    snapshot: &Snapshot,
+    grants: &UserGrants,
+    channel_id: &str,
+    permission: &str,
+    granted_default: bool,
+) -> bool {
+    let (groups, user_permissions) = permission_sources(snapshot, grants, channel_id);
+    resolve_permission(permission, granted_default, &groups, &user_permissions)
+}
+
+/// Explains the permission check of a user with the groups of the cache and the memberships and permissions of the
+/// user
+pub fn explain_user_permission(
+    snapshot: &Snapshot,
+    grants: &UserGrants,
+    channel_id: &str,
+    permission: &str,
+    granted_default: bool,
+) -> PermissionTrace {
+    let (groups, user_permissions) = permission_sources(snapshot, grants, channel_id);
+    explain_permission(permission, granted_default, &groups, &user_permissions)
+}
+
+impl From<DecisionStep> for PermissionDecision {
+    fn from(step: DecisionStep) -> PermissionDecision {
+        let mut decision = PermissionDecision {
+            verdict: step.verdict,
+            matched_permission: step.matched.map(|matched| Permission {
+                permission: matched.node,
+                granted: matched.granted,
+            }),
+            ..Default::default()
+        };
+        match step.source {
+            DecisionSource::Default => decision.set_source(Source::Default),
+            DecisionSource::Group {
+                group_id,
+                group_name,
+                group_sorting,
+            } => {
+                decision.set_source(Source::Group);
+                decision.group_id = group_id;
+                decision.group_name = group_name;
+                decision.group_sorting = group_sorting;
+            }
+            DecisionSource::User => decision.set_source(Source::User),
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(group_id: i32, group_sorting: i32, permissions: &[(&str, bool)]) -> (Group, Vec<GroupPermission>) {
+        let group = Group {
+            group_id,
+            group_name: format!("group{}", group_id),
+            bonus_payout: 0,
+            group_sorting,
+        };
+        let permissions = permissions
+            .iter()
+            .map(|(permission, granted)| GroupPermission {
+                group_id,
+                permission: permission.to_string(),
+                granted: *granted,
+            })
+            .collect();
+        (group, permissions)
+    }
+
+    fn user(permissions: &[(&str, bool)]) -> Vec<UserPermission> {
+        permissions
+            .iter()
+            .map(|(permission, granted)| UserPermission {
+                channel_id: "UC123".to_string(),
+                permission: permission.to_string(),
+                granted: *granted,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wildcards_match_nodes_below_them() {
+        assert_eq!(node_specificity("chat.command.songrequest", "chat.command.songrequest"), Some(3));
+        assert_eq!(node_specificity("chat.command.*", "chat.command.songrequest"), Some(2));
+        assert_eq!(node_specificity("chat.*", "chat.command.songrequest"), Some(1));
+        assert_eq!(node_specificity("*", "chat.command.songrequest"), Some(0));
+    }
+
+    #[test]
+    fn wildcards_only_match_whole_segments() {
+        assert_eq!(node_specificity("chat.*", "chatter.command"), None);
+        assert_eq!(node_specificity("chat.command.*", "chat.command"), None);
+        assert_eq!(node_specificity("chat.command", "chat.command.songrequest"), None);
+        assert_eq!(node_specificity("chat*", "chat.command"), None);
+    }
+
+    #[test]
+    fn default_is_used_without_matching_nodes() {
+        let groups = vec![group(1, 0, &[("chat.other", false)])];
+        assert!(resolve_permission("chat.command.songrequest", true, &groups, &[]));
+        assert!(!resolve_permission("chat.command.songrequest", false, &groups, &[]));
+    }
+
+    #[test]
+    fn most_specific_node_of_a_group_wins() {
+        let groups = vec![group(
+            1,
+            0,
+            &[("chat.*", true), ("chat.command.songrequest", false), ("chat.command.*", true)],
+        )];
+        assert!(!resolve_permission("chat.command.songrequest", true, &groups, &[]));
+        assert!(resolve_permission("chat.command.skip", false, &groups, &[]));
+        assert!(resolve_permission("chat.emotes", false, &groups, &[]));
+    }
+
+    #[test]
+    fn higher_sorted_groups_override_lower_sorted_groups() {
+        let groups = vec![
+            group(1, 10, &[("chat.command.*", true)]),
+            group(2, 0, &[("chat.command.songrequest", false)]),
+        ];
+        assert!(resolve_permission("chat.command.songrequest", false, &groups, &[]));
+    }
+
+    #[test]
+    fn user_permissions_override_groups() {
+        let groups = vec![group(1, 0, &[("chat.command.songrequest", true)])];
+        let user_permissions = user(&[("chat.*", false)]);
+        assert!(!resolve_permission("chat.command.songrequest", true, &groups, &user_permissions));
+    }
+
+    #[test]
+    fn explanation_lists_every_consulted_step() {
+        let groups = vec![
+            group(1, 10, &[("chat.*", false)]),
+            group(2, 0, &[("chat.command.*", true)]),
+        ];
+        let trace = explain_permission("chat.command.songrequest", true, &groups, &[]);
+
+        assert_eq!(trace.steps.len(), 4);
+        assert!(matches!(trace.steps[0].source, DecisionSource::Default));
+        assert!(matches!(trace.steps[1].source, DecisionSource::Group { group_id: 2, .. }));
+        assert_eq!(trace.steps[1].matched.as_ref().unwrap().node, "chat.command.*");
+        assert!(trace.steps[1].verdict);
+        assert!(matches!(trace.steps[2].source, DecisionSource::Group { group_id: 1, .. }));
+        assert!(!trace.steps[2].verdict);
+        assert!(matches!(trace.steps[3].source, DecisionSource::User));
+        assert!(trace.steps[3].matched.is_none());
+        assert!(!trace.granted);
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::audit::{self, Target};
+use crate::models::{naive_to_rfc3339, AuditEntry, Group, RankHistory, Session, Transaction, User, UserPermission};
+use crate::money;
+use crate::schema::{bpp_groups_users, bpp_rank_history, bpp_sessions, bpp_transactions, bpp_users, bpp_users_permissions};
+
+/// The display name erased users are kept under
+const ERASED_DISPLAY_NAME: &str = "Erased user";
+
+/// Collects everything stored about a user into a JSON document, deleted users included.
+///
+/// Money is given in minor units and durations in seconds, like everywhere else in the API.
+pub fn export_user_data(channel_id: &str, conn: &PgConnection) -> QueryResult<String> {
+    let user = bpp_users::table.find(channel_id).first::<User>(conn)?;
+    let groups = Group::get_groups_for_user(channel_id.to_string(), conn)?;
+    let permissions = UserPermission::get_permissions_for_user(channel_id.to_string(), conn)?;
+    let transactions = bpp_transactions::table
+        .filter(bpp_transactions::channel_id.eq(channel_id))
+        .order(bpp_transactions::transaction_id.asc())
+        .load::<Transaction>(conn)?;
+    let rank_history = bpp_rank_history::table
+        .filter(bpp_rank_history::channel_id.eq(channel_id))
+        .order(bpp_rank_history::history_id.asc())
+        .load::<RankHistory>(conn)?;
+    let sessions = bpp_sessions::table
+        .filter(bpp_sessions::channel_id.eq(channel_id))
+        .order(bpp_sessions::session_id.asc())
+        .load::<Session>(conn)?;
+    let audit_entries = audit::entries_of_user(channel_id, conn)?;
+
+    let data = json!({
+        "channel_id": user.channel_id,
+        "exported_at": naive_to_rfc3339(&Utc::now().naive_utc()),
+        "user": {
+            "display_name": user.display_name,
+            "hours_seconds": user.hours_seconds,
+            "money": money::to_minor_units(&user.money),
+            "first_seen_at": naive_to_rfc3339(&user.first_seen_at),
+            "last_seen_at": naive_to_rfc3339(&user.last_seen_at),
+            "deleted_at": user.deleted_at.as_ref().map(naive_to_rfc3339),
+        },
+        "group_memberships": groups
+            .iter()
+            .map(|group| json!({ "group_id": group.group_id, "group_name": group.group_name }))
+            .collect::<Vec<Value>>(),
+        "permissions": permissions
+            .iter()
+            .map(|permission| json!({ "permission": permission.permission, "granted": permission.granted }))
+            .collect::<Vec<Value>>(),
+        "transactions": transactions
+            .iter()
+            .map(|transaction| json!({
+                "transaction_id": transaction.transaction_id,
+                "channel_id": transaction.channel_id,
+                "amount": money::to_minor_units(&transaction.amount),
+                "reason": transaction.reason,
+                "actor": transaction.actor,
+                "created_at": naive_to_rfc3339(&transaction.created_at),
+            }))
+            .collect::<Vec<Value>>(),
+        "rank_history": rank_history
+            .iter()
+            .map(|change| json!({
+                "old_rank_name": change.old_rank_name,
+                "new_rank_name": change.new_rank_name,
+                "changed_at": naive_to_rfc3339(&change.changed_at),
+            }))
+            .collect::<Vec<Value>>(),
+        "sessions": sessions
+            .iter()
+            .map(|session| json!({
+                "broadcast_id": session.broadcast_id,
+                "started_at": naive_to_rfc3339(&session.started_at),
+                "ended_at": naive_to_rfc3339(&session.ended_at),
+                "message_count": session.message_count,
+                "duration_seconds": session.duration_seconds,
+            }))
+            .collect::<Vec<Value>>(),
+        // Changes the user made or which were made to them through the API
+        "audit_log": audit_entries
+            .iter()
+            .map(|entry: &AuditEntry| json!({
+                "actor": entry.actor,
+                "rpc": entry.rpc,
+                "target_type": entry.target_type,
+                "target_id": entry.target_id,
+                "before": entry.before_value,
+                "after": entry.after_value,
+                "created_at": naive_to_rfc3339(&entry.created_at),
+            }))
+            .collect::<Vec<Value>>(),
+    });
+    Ok(data.to_string())
+}
+
+/// Removes everything identifying a user, fails with `NotFound` if the user doesn't exist.
+///
+/// Memberships and permissions are deleted. Hours, money, the ledger, the rank history and the sessions are moved to a
+/// new user with a random channel id, so leaderboards, broadcast statistics and the ledger of the other users still
+/// add up. The new user keeps whether the user was deleted, a deleted user stays out of leaderboards and is purged
+/// like any other, and its first and last seen times are set to the Unix epoch. Messages the channel sends afterwards
+/// are tracked as a new user. The audit log refers to the new user as well, the erasure itself is recorded for it too.
+pub fn erase_user(channel_id: &str, actor: &str, conn: &PgConnection) -> QueryResult<()> {
+    conn.transaction(|| {
+        let user = match User::get_for_update(channel_id, conn)? {
+            Some(user) => user,
+            None => return Err(diesel::result::Error::NotFound),
+        };
+        let pseudonym = format!("erased-{:016x}", rand::thread_rng().gen::<u64>());
+        let erased = User {
+            channel_id: pseudonym.clone(),
+            display_name: ERASED_DISPLAY_NAME.to_string(),
+            first_seen_at: NaiveDateTime::from_timestamp(0, 0),
+            last_seen_at: NaiveDateTime::from_timestamp(0, 0),
+            ..user
+        };
+        diesel::insert_into(bpp_users::table).values(&erased).execute(conn)?;
+
+        diesel::update(bpp_transactions::table.filter(bpp_transactions::channel_id.eq(channel_id)))
+            .set(bpp_transactions::channel_id.eq(&pseudonym))
+            .execute(conn)?;
+        diesel::update(bpp_transactions::table.filter(bpp_transactions::actor.eq(channel_id)))
+            .set(bpp_transactions::actor.eq(&pseudonym))
+            .execute(conn)?;
+        diesel::update(bpp_rank_history::table.filter(bpp_rank_history::channel_id.eq(channel_id)))
+            .set(bpp_rank_history::channel_id.eq(&pseudonym))
+            .execute(conn)?;
+        diesel::update(bpp_sessions::table.filter(bpp_sessions::channel_id.eq(channel_id)))
+            .set(bpp_sessions::channel_id.eq(&pseudonym))
+            .execute(conn)?;
+
+        diesel::delete(bpp_groups_users::table.filter(bpp_groups_users::channel_id.eq(channel_id))).execute(conn)?;
+        diesel::delete(bpp_users_permissions::table.filter(bpp_users_permissions::channel_id.eq(channel_id)))
+            .execute(conn)?;
+        diesel::delete(bpp_users::table.find(channel_id)).execute(conn)?;
+
+        audit::pseudonymise_user(channel_id, &pseudonym, conn)?;
+        // An erased user erasing themselves shouldn't be named in the log either
+        let actor = if actor == channel_id { pseudonym.as_str() } else { actor };
+        audit::record(actor, "EraseUser", Target::User(pseudonym.clone()), None, None, conn)?;
+        Ok(())
+    })
+}
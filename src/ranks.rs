@@ -0,0 +1,105 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Bool, Int4, Nullable, Text};
+
+use crate::models::{naive_to_timestamp, RankHistory};
+use crate::paging::Page;
+use crate::schema::bpp_rank_history;
+use crate::userservice::RankChange;
+
+/// Stores the rank every matching user currently qualifies for and, unless `$3` is false, records a history entry for
+/// each user whose rank changed. This uses the same rules as `User::get_active_rank`, ranks in `excluded_rank_ids` are
+/// ignored.
+const REFRESH_RANKS_SQL: &str = "
+WITH computed AS (
+    SELECT bpp_users.channel_id, bpp_users.rank_id AS old_rank_id, (
+        SELECT bpp_ranks.rank_id
+        FROM bpp_ranks
+        WHERE bpp_ranks.hour_requirement_seconds <= bpp_users.hours_seconds
+            AND bpp_ranks.rank_id <> ALL($2)
+        ORDER BY bpp_ranks.rank_sorting DESC
+        LIMIT 1
+    ) AS new_rank_id
+    FROM bpp_users
+    WHERE $1 IS NULL OR bpp_users.channel_id = $1
+), changed AS (
+    UPDATE bpp_users
+    SET rank_id = computed.new_rank_id
+    FROM computed
+    WHERE bpp_users.channel_id = computed.channel_id
+        AND bpp_users.rank_id IS DISTINCT FROM computed.new_rank_id
+    RETURNING bpp_users.channel_id, computed.old_rank_id, computed.new_rank_id
+)
+INSERT INTO bpp_rank_history (channel_id, old_rank_id, old_rank_name, new_rank_id, new_rank_name, changed_at)
+SELECT changed.channel_id,
+    changed.old_rank_id,
+    COALESCE(old_rank.rank_name, 'default'),
+    changed.new_rank_id,
+    COALESCE(new_rank.rank_name, 'default'),
+    NOW() AT TIME ZONE 'utc'
+FROM changed
+LEFT JOIN bpp_ranks old_rank ON old_rank.rank_id = changed.old_rank_id
+LEFT JOIN bpp_ranks new_rank ON new_rank.rank_id = changed.new_rank_id
+WHERE $3
+RETURNING *";
+
+/// Updates the stored rank of a single user, or of all users if no channel id is given, and returns the recorded
+/// rank changes.
+///
+/// This has to be called whenever the hours of a user or the ranks themselves change. Ranks which are about to be
+/// deleted can be excluded, so the history still knows the name of the rank a user lost.
+pub fn refresh_ranks(
+    channel_id: Option<&str>,
+    excluded_rank_ids: &[i32],
+    conn: &PgConnection,
+) -> QueryResult<Vec<RankHistory>> {
+    diesel::sql_query(REFRESH_RANKS_SQL)
+        .bind::<Nullable<Text>, _>(channel_id)
+        .bind::<Array<Int4>, _>(excluded_rank_ids)
+        .bind::<Bool, _>(true)
+        .load(conn)
+}
+
+/// Stores the rank of a user who was just created. Starting out with a rank isn't a rank change, so nothing is
+/// recorded in the history.
+pub fn assign_initial_rank(channel_id: &str, conn: &PgConnection) -> QueryResult<()> {
+    diesel::sql_query(REFRESH_RANKS_SQL)
+        .bind::<Nullable<Text>, _>(Some(channel_id))
+        .bind::<Array<Int4>, _>(&[] as &[i32])
+        .bind::<Bool, _>(false)
+        .load::<RankHistory>(conn)?;
+    Ok(())
+}
+
+/// Loads one page of rank changes, newest first, together with the total number of matching changes.
+///
+/// An empty channel id matches the changes of all users.
+pub fn get_rank_history(channel_id: &str, page: &Page, conn: &PgConnection) -> QueryResult<(i64, Vec<RankHistory>)> {
+    let mut count_query = bpp_rank_history::table.into_boxed();
+    let mut query = bpp_rank_history::table.into_boxed();
+    if !channel_id.is_empty() {
+        count_query = count_query.filter(bpp_rank_history::channel_id.eq(channel_id.to_string()));
+        query = query.filter(bpp_rank_history::channel_id.eq(channel_id.to_string()));
+    }
+
+    let total = count_query.count().get_result(conn)?;
+    let changes = query
+        .order((bpp_rank_history::changed_at.desc(), bpp_rank_history::history_id.desc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<RankHistory>(conn)?;
+    Ok((total, changes))
+}
+
+impl From<RankHistory> for RankChange {
+    fn from(history: RankHistory) -> RankChange {
+        RankChange {
+            history_id: history.history_id,
+            channel_id: history.channel_id,
+            old_rank_id: history.old_rank_id.unwrap_or(0),
+            old_rank_name: history.old_rank_name,
+            new_rank_id: history.new_rank_id.unwrap_or(0),
+            new_rank_name: history.new_rank_name,
+            changed_at: Some(naive_to_timestamp(&history.changed_at)),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use log::{error, info};
+
+use crate::models::User;
+use crate::DbPool;
+
+/// How often deleted users are checked for the end of their retention
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes users for good once they were deleted more than `retention_days` days ago. Runs until the process ends.
+pub async fn purge_deleted_users(pool: DbPool, retention_days: i32) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now().naive_utc();
+        let deleted_before = match now.checked_sub_signed(chrono::Duration::days(retention_days as i64)) {
+            Some(deleted_before) => deleted_before,
+            None => {
+                error!("A retention of {} days reaches before the earliest supported date", retention_days);
+                continue;
+            }
+        };
+        match purge(&pool, deleted_before) {
+            Ok(purged) if purged.is_empty() => {}
+            Ok(purged) => info!("Purged {} users deleted before {}", purged.len(), deleted_before),
+            Err(e) => error!("Failed to purge deleted users: {}", e),
+        }
+    }
+}
+
+fn purge(pool: &DbPool, deleted_before: NaiveDateTime) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let conn = pool.get()?;
+    Ok(User::purge_deleted(deleted_before, &conn)?)
+}
@@ -1,3 +1,27 @@
+table! {
+    bpp_audit_log (audit_id) {
+        audit_id -> Int8,
+        actor -> Varchar,
+        rpc -> Varchar,
+        target_type -> Varchar,
+        target_id -> Varchar,
+        before_value -> Nullable<Jsonb>,
+        after_value -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    bpp_broadcasts (broadcast_id) {
+        broadcast_id -> Int4,
+        title -> Varchar,
+        video_id -> Varchar,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        state -> Varchar,
+    }
+}
+
 table! {
     bpp_groups (group_id) {
         group_id -> Int4,
@@ -22,6 +46,18 @@ table! {
     }
 }
 
+table! {
+    bpp_rank_history (history_id) {
+        history_id -> Int8,
+        channel_id -> Varchar,
+        old_rank_id -> Nullable<Int4>,
+        old_rank_name -> Varchar,
+        new_rank_id -> Nullable<Int4>,
+        new_rank_name -> Varchar,
+        changed_at -> Timestamp,
+    }
+}
+
 table! {
     bpp_ranks (rank_id) {
         rank_id -> Int4,
@@ -32,14 +68,39 @@ table! {
     }
 }
 
+table! {
+    bpp_sessions (session_id) {
+        session_id -> Int8,
+        channel_id -> Varchar,
+        broadcast_id -> Nullable<Int4>,
+        started_at -> Timestamp,
+        ended_at -> Timestamp,
+        message_count -> Int4,
+        duration_seconds -> Int8,
+    }
+}
+
+table! {
+    bpp_transactions (transaction_id) {
+        transaction_id -> Int8,
+        channel_id -> Varchar,
+        amount -> Numeric,
+        reason -> Varchar,
+        actor -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     bpp_users (channel_id) {
         channel_id -> Varchar,
         display_name -> Varchar,
         hours_seconds -> Int8,
-        money -> Float8,
+        money -> Numeric,
         first_seen_at -> Timestamp,
         last_seen_at -> Timestamp,
+        rank_id -> Nullable<Int4>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -54,13 +115,22 @@ table! {
 joinable!(bpp_groups_permissions -> bpp_groups (group_id));
 joinable!(bpp_groups_users -> bpp_groups (group_id));
 joinable!(bpp_groups_users -> bpp_users (channel_id));
+joinable!(bpp_rank_history -> bpp_users (channel_id));
+joinable!(bpp_sessions -> bpp_broadcasts (broadcast_id));
+joinable!(bpp_sessions -> bpp_users (channel_id));
+joinable!(bpp_users -> bpp_ranks (rank_id));
 joinable!(bpp_users_permissions -> bpp_users (channel_id));
 
 allow_tables_to_appear_in_same_query!(
+    bpp_audit_log,
+    bpp_broadcasts,
     bpp_groups,
     bpp_groups_permissions,
     bpp_groups_users,
+    bpp_rank_history,
     bpp_ranks,
+    bpp_sessions,
+    bpp_transactions,
     bpp_users,
     bpp_users_permissions,
 );
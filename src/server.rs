@@ -1,3 +1,6 @@
+// The derives of diesel 1.4 implement its traits inside of functions, which newer compilers warn about
+#![allow(unknown_lints, non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 #[macro_use]
@@ -5,36 +8,62 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate serde;
 
+use std::convert::TryFrom;
 use std::env;
+use std::sync::Arc;
 use std::net::SocketAddr;
 
-use ::log::{debug, error, info};
-use chrono::NaiveDateTime;
+use ::log::{debug, info};
+use bigdecimal::{BigDecimal, Zero};
 use chrono::Utc;
 use diesel::prelude::*;
-use diesel::r2d2::ConnectionManager;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
 use diesel::PgConnection;
 use diesel_migrations::embed_migrations;
 use dotenv::dotenv;
-use models::{Group, GroupPermission, InsertGroup, InsertRank, User, UserPermission, Rank};
+use models::{
+    timestamp_to_naive, Broadcast, Group, GroupPermission, GroupUser, InsertGroup, InsertRank, RankHistory, User, Rank,
+};
 use r2d2::Pool;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::Response;
 use tonic::Status;
-use tonic::transport::Channel;
 use tonic::Request;
 
 use userservice::user_service_server::{UserService, UserServiceServer};
-use userservice::{BppGroup, BppUser};
-use youtubeservice::you_tube_service_client::YouTubeServiceClient;
-
+use userservice::{BppGroup, UserEvent};
+
+use crate::audit::Target;
+use crate::cache::{Cache, Snapshot, UserGrants};
+use crate::error::ServiceError;
+use crate::events::EventBus;
+use crate::ingestion::Ingestion;
+use crate::ledger::TransactionReason;
 use crate::log::setup_log;
+use crate::paging::{Page, MAX_PAGE_SIZE};
+use crate::sessions::BroadcastState;
 use crate::settings::Settings;
 
 mod settings;
 mod log;
+mod audit;
+mod cache;
+mod error;
+mod events;
+mod filters;
+mod ingestion;
+mod leaderboard;
+mod ledger;
 mod macros;
 mod models;
+mod money;
+mod paging;
+mod permissions;
+mod privacy;
+mod ranks;
+mod retention;
 mod schema;
+mod sessions;
 
 embed_migrations!();
 
@@ -49,6 +78,9 @@ pub mod userservice {
 type Void = Result<(), Box<dyn std::error::Error>>;
 type DbPool = Pool<ConnectionManager<PgConnection>>;
 
+/// The metadata key clients use to tell who is performing a request
+const ACTOR_METADATA_KEY: &str = "x-bpp-actor";
+
 pub fn connect_to_database() -> Pool<ConnectionManager<PgConnection>> {
     // Get the database URL from the environment
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
@@ -56,113 +88,400 @@ pub fn connect_to_database() -> Pool<ConnectionManager<PgConnection>> {
     // Create a connection pool of 10 connections
     let pool = Pool::builder().max_size(10).build(manager).unwrap();
 
-    // Run migrations
-    let _ = embedded_migrations::run_with_output(&pool.get().unwrap(), &mut std::io::stdout());
+    // Run migrations, the service can't work on a partially migrated schema
+    embedded_migrations::run_with_output(&pool.get().unwrap(), &mut std::io::stdout())
+        .expect("Failed to run the database migrations");
 
     pool
 }
 
-fn calculate_hours_and_money(user: &mut User, now: &NaiveDateTime, settings: Settings, conn: &PgConnection) {
-    let new_hours_seconds;
-    let hours_duration = chrono::Duration::seconds(user.hours_seconds);
-    let new_duration = *now - user.last_seen_at;
-    debug!("Between the last time the user was seen and now, {} seconds have passed", new_duration.num_seconds());
-    let hours = hours_duration + new_duration;
-    new_hours_seconds = hours.num_seconds();
-    debug!(
-        "Updating hours of {} ({}) from {}s to {}s",
-        user.channel_id,
-        user.display_name,
-        user.hours_seconds,
-        new_hours_seconds
-    );
-
-    user.hours_seconds = new_hours_seconds;
-
-    // Grant x money per minute
-    let mut money_per_minute: f64 = settings.default_payout as f64;
-    let user_groups = Group::get_groups_for_user(user.channel_id.clone(), conn);
-    for group in user_groups {
-        money_per_minute += group.bonus_payout as f64;
-    }
-    let money_per_second: f64 = money_per_minute / 60.0;
-
-    let new_money = user.money + money_per_second * new_duration.num_seconds() as f64;
-    debug!(
-        "Updating money of {} ({}) from {:.2} to {:.2}",
-        user.channel_id, user.display_name, user.money, new_money
-    );
-    user.money = new_money;
+/// Returns who is performing a request, as told by the client in the request metadata
+fn actor_from_request<T>(request: &Request<T>) -> String {
+    request
+        .metadata()
+        .get(ACTOR_METADATA_KEY)
+        .and_then(|actor| actor.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string()
 }
 
-async fn fetch_users_from_messages(
-    youtube_client: &mut YouTubeServiceClient<Channel>,
-    pool: &DbPool,
-) -> Void {
-    let mut stream = youtube_client
-        .subscribe_messages(Request::new(()))
-        .await?
-        .into_inner();
-
-    while let Some(message) = stream.message().await? {
-        let conn = pool.get()?;
-        let now = Utc::now().naive_utc();
-        let mut user = if User::check_if_exists(&message.channel_id, &conn) {
-            debug!("Updating existing user {}", &message.channel_id);
-            // Update the user
-            User::get_from_database(&message.channel_id, &conn).unwrap()
+/// Makes sure the group and all of the users exist before their memberships are changed
+fn check_membership_targets(group_id: i32, channel_ids: &[String], conn: &PgConnection) -> Result<(), ServiceError> {
+    ServiceError::not_found(Group::get_from_database(&group_id, conn), "Group not found")?;
+
+    let missing_users = User::find_missing(channel_ids, conn)?;
+    if !missing_users.is_empty() {
+        return Err(ServiceError::NotFound(format!("Users not found: {}", missing_users.join(", "))));
+    }
+
+    Ok(())
+}
+
+/// Deletes the groups together with their memberships and permissions and returns the memberships they had.
+///
+/// Nothing is deleted if one of the groups doesn't exist, or with `only_if_empty` if one of them still has members.
+fn delete_groups(
+    group_ids: &[i32],
+    only_if_empty: bool,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> Result<Vec<GroupUser>, ServiceError> {
+    conn.transaction(|| {
+        // Lock the groups, so no members can be added between the check and the delete
+        let groups = Group::get_many_for_update(group_ids, conn)?;
+        let mut missing_groups: Vec<i32> = group_ids
+            .iter()
+            .filter(|group_id| !groups.iter().any(|group| group.group_id == **group_id))
+            .cloned()
+            .collect();
+        if !missing_groups.is_empty() {
+            missing_groups.sort_unstable();
+            missing_groups.dedup();
+            let missing_groups: Vec<String> = missing_groups.iter().map(|group_id| group_id.to_string()).collect();
+            return Err(ServiceError::NotFound(format!("Groups not found: {}", missing_groups.join(", "))));
+        }
+        if only_if_empty {
+            let non_empty = GroupUser::groups_with_members(group_ids, conn)?;
+            if !non_empty.is_empty() {
+                let non_empty: Vec<String> = non_empty.iter().map(|group_id| group_id.to_string()).collect();
+                return Err(ServiceError::FailedPrecondition(format!(
+                    "Groups still have members: {}",
+                    non_empty.join(", ")
+                )));
+            }
+        }
+        let memberships = Group::delete_many(group_ids, conn)?;
+        for group in &groups {
+            audit::record(actor, rpc, Target::Group(group.group_id), Some(audit::group_value(group)), None, conn)?;
+        }
+        Ok(memberships)
+    })
+}
+
+/// Saves a group sent by a client and records how it changed, fails if the group doesn't exist
+fn save_group(group: &Group, actor: &str, rpc: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    conn.transaction(|| {
+        let not_found = || ServiceError::NotFound("Group not found".to_string());
+        let previous = Group::get_from_database(&group.group_id, conn).optional()?.ok_or_else(not_found)?;
+        // Saving only updates, so a group deleted in the meantime isn't recreated
+        if group.save_to_database(conn)? == 0 {
+            return Err(not_found());
+        }
+        audit::record(
+            actor,
+            rpc,
+            Target::Group(group.group_id),
+            Some(audit::group_value(&previous)),
+            Some(audit::group_value(group)),
+            conn,
+        )?;
+        Ok(())
+    })
+}
+
+/// Saves a rank sent by a client and records how it changed, fails if the rank doesn't exist
+fn save_rank(rank: &Rank, actor: &str, rpc: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    conn.transaction(|| {
+        let not_found = || ServiceError::NotFound("Rank not found".to_string());
+        let previous = Rank::get_from_database(&rank.rank_id, conn).optional()?.ok_or_else(not_found)?;
+        // Saving only updates, so a rank deleted in the meantime isn't recreated
+        if rank.save_to_database(conn)? == 0 {
+            return Err(not_found());
+        }
+        audit::record(
+            actor,
+            rpc,
+            Target::Rank(rank.rank_id),
+            Some(audit::rank_value(&previous)),
+            Some(audit::rank_value(rank)),
+            conn,
+        )?;
+        Ok(())
+    })
+}
+
+/// Grants or revokes a permission of a user and records the change. Returns whether the permission changed.
+fn set_user_permission(
+    user_permission: &models::UserPermission,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> QueryResult<bool> {
+    use schema::bpp_users_permissions::dsl::*;
+    conn.transaction(|| {
+        let previous = bpp_users_permissions
+            .find((&user_permission.channel_id, &user_permission.permission))
+            .select(granted)
+            .for_update()
+            .first::<bool>(conn)
+            .optional()?;
+        if previous == Some(user_permission.granted) {
+            return Ok(false);
+        }
+        diesel::insert_into(bpp_users_permissions)
+            .values(user_permission)
+            .on_conflict((channel_id, permission))
+            .do_update()
+            .set(granted.eq(user_permission.granted))
+            .execute(conn)?;
+        let before = previous.map(|previous| audit::permission_value(&user_permission.permission, previous));
+        let after = Some(audit::permission_value(&user_permission.permission, user_permission.granted));
+        audit::record(actor, rpc, Target::User(user_permission.channel_id.clone()), before, after, conn)?;
+        Ok(true)
+    })
+}
+
+/// Grants or revokes a permission of a group and records the change. Returns whether the permission changed.
+fn set_group_permission(
+    group_permission: &models::GroupPermission,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> QueryResult<bool> {
+    use schema::bpp_groups_permissions::dsl::*;
+    conn.transaction(|| {
+        let previous = bpp_groups_permissions
+            .find((group_permission.group_id, &group_permission.permission))
+            .select(granted)
+            .for_update()
+            .first::<bool>(conn)
+            .optional()?;
+        if previous == Some(group_permission.granted) {
+            return Ok(false);
+        }
+        diesel::insert_into(bpp_groups_permissions)
+            .values(group_permission)
+            .on_conflict((group_id, permission))
+            .do_update()
+            .set(granted.eq(group_permission.granted))
+            .execute(conn)?;
+        let before = previous.map(|previous| audit::permission_value(&group_permission.permission, previous));
+        let after = Some(audit::permission_value(&group_permission.permission, group_permission.granted));
+        audit::record(actor, rpc, Target::Group(group_permission.group_id), before, after, conn)?;
+        Ok(true)
+    })
+}
+
+/// Adds the users to or removes them from a group and records every membership which changed. Returns the channel ids
+/// of these users.
+fn change_memberships(
+    group_id: i32,
+    channel_ids: &[String],
+    add: bool,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> QueryResult<Vec<String>> {
+    conn.transaction(|| {
+        let changed = if add {
+            GroupUser::add_users(group_id, channel_ids, conn)?
         } else {
-            debug!("Creating new user {}", &message.channel_id);
-            // Create the user
-            User::new(
-                message.channel_id.clone(),
-                message.display_name.clone(),
-                0,
-                0 as f64,
-                now,
-                now,
-            )
+            GroupUser::remove_users(group_id, channel_ids, conn)?
         };
+        for channel_id in &changed {
+            let membership = Some(audit::membership_value(group_id));
+            let (before, after) = if add { (None, membership) } else { (membership, None) };
+            audit::record(actor, rpc, Target::User(channel_id.clone()), before, after, conn)?;
+        }
+        Ok(changed)
+    })
+}
+
+/// Records how the broadcasts changed, given the broadcast which was running before the change and the one running
+/// afterwards
+fn record_broadcast_changes(
+    previous: Option<&Broadcast>,
+    current: Option<&Broadcast>,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> QueryResult<()> {
+    if let Some(previous) = previous {
+        let before = audit::broadcast_value(previous);
+        let after = sessions::get_broadcast(previous.broadcast_id, conn)?;
+        let after = after.map(|broadcast| audit::broadcast_value(&broadcast));
+        if after.as_ref() != Some(&before) {
+            audit::record(actor, rpc, Target::Broadcast(previous.broadcast_id), Some(before), after, conn)?;
+        }
+    }
+    if let Some(current) = current {
+        if previous.map(|previous| previous.broadcast_id) != Some(current.broadcast_id) {
+            let after = Some(audit::broadcast_value(current));
+            audit::record(actor, rpc, Target::Broadcast(current.broadcast_id), None, after, conn)?;
+        }
+    }
+    Ok(())
+}
 
-        user.display_name = message.display_name.clone();
+/// Marks the users as deleted and records their deletion.
+///
+/// Nothing is deleted if one of the users doesn't exist, deleted users count as missing.
+fn soft_delete_users(channel_ids: &[String], actor: &str, rpc: &str, conn: &PgConnection) -> Result<(), ServiceError> {
+    conn.transaction(|| {
+        let deleted = User::soft_delete(channel_ids, Utc::now().naive_utc(), conn)?;
+        let mut missing_users: Vec<String> = channel_ids
+            .iter()
+            .filter(|channel_id| !deleted.iter().any(|user| &user.channel_id == *channel_id))
+            .cloned()
+            .collect();
+        if !missing_users.is_empty() {
+            missing_users.sort_unstable();
+            missing_users.dedup();
+            return Err(ServiceError::NotFound(format!("Users not found: {}", missing_users.join(", "))));
+        }
+        for user in deleted {
+            let previous = User {
+                deleted_at: None,
+                ..user.clone()
+            };
+            audit::record(
+                actor,
+                rpc,
+                Target::User(user.channel_id.clone()),
+                Some(audit::user_value(&previous)),
+                Some(audit::user_value(&user)),
+                conn,
+            )?;
+        }
+        Ok(())
+    })
+}
 
-        let settings = Settings::new()?;
+/// Saves a user sent by a client, books the change of their balance in the ledger and returns the events describing
+/// the change. Deleted users have to be restored before they can be saved again.
+fn save_user_with_ledger(
+    user: &User,
+    actor: &str,
+    rpc: &str,
+    conn: &PgConnection,
+) -> Result<Vec<UserEvent>, ServiceError> {
+    conn.transaction(|| {
+        let previous = User::get_for_update(&user.channel_id, conn)?;
+        if previous.as_ref().map(User::is_deleted).unwrap_or(false) {
+            return Err(ServiceError::FailedPrecondition(format!(
+                "User {} is deleted, restore them first",
+                user.channel_id
+            )));
+        }
+        user.save_to_database(conn)?;
+        audit::record(
+            actor,
+            rpc,
+            Target::User(user.channel_id.clone()),
+            previous.as_ref().map(audit::user_value),
+            Some(audit::user_value(user)),
+            conn,
+        )?;
+
+        let (amount, reason) = match &previous {
+            Some(previous) => (&user.money - &previous.money, TransactionReason::Adjustment),
+            None => (user.money.clone(), TransactionReason::Opening),
+        };
+        if !amount.is_zero() {
+            ledger::record(&user.channel_id, amount, reason, actor, conn)?;
+        }
+        let rank_changes = if previous.is_some() {
+            ranks::refresh_ranks(Some(&user.channel_id), &[], conn)?
+        } else {
+            ranks::assign_initial_rank(&user.channel_id, conn)?;
+            Vec::new()
+        };
+
+        let mut changes = user_change_events(previous.as_ref(), user, TransactionReason::Adjustment, conn)?;
+        changes.extend(rank_changes.into_iter().map(events::rank_changed));
+        Ok(changes)
+    })
+}
 
-        // Determine if user was active before this message and if so, update the hours
-        // if the user has been last seen less than the configured timeframe, update the hours
-        if user.last_seen_at + chrono::Duration::seconds(settings.active_time as i64) > now {
-            calculate_hours_and_money(&mut user, &now, settings, &conn);
+/// Describes how a user changed compared to their previous state, or their creation if there is none.
+///
+/// A change of their money is attributed to `money_reason`, unless the user was just created. Rank changes are
+/// described by the history entries [`ranks::refresh_ranks`] records.
+fn user_change_events(
+    previous: Option<&User>,
+    user: &User,
+    money_reason: TransactionReason,
+    conn: &PgConnection,
+) -> QueryResult<Vec<UserEvent>> {
+    let mut changes = Vec::new();
+    let (previous_money, money_reason) = match previous {
+        Some(previous) => {
+            let changed_fields = user.changed_fields(previous);
+            if changed_fields.is_empty() {
+                return Ok(changes);
+            }
+            changes.push(events::user_updated(user.to_userservice_user(conn)?, changed_fields));
+            (previous.money.clone(), money_reason)
+        }
+        None => {
+            changes.push(events::user_created(user.to_userservice_user(conn)?));
+            (BigDecimal::zero(), TransactionReason::Opening)
         }
-        user.last_seen_at = now;
+    };
 
-        // Update the user
-        user.save_to_database(&conn).unwrap();
+    let delta = &user.money - &previous_money;
+    if !delta.is_zero() {
+        changes.push(events::money_adjusted(&user.channel_id, &delta, &user.money, money_reason));
     }
-
-    Ok(())
+    Ok(changes)
 }
 
 pub struct UserServer {
-    database_pool: DbPool
+    database_pool: DbPool,
+    event_bus: EventBus,
+    cache: Cache,
+    ingestion: Ingestion,
+}
+
+impl UserServer {
+    fn connection(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, ServiceError> {
+        Ok(self.database_pool.get()?)
+    }
+
+    fn cached_snapshot(&self, conn: &PgConnection) -> Result<Arc<Snapshot>, ServiceError> {
+        Ok(self.cache.get(conn)?)
+    }
+
+    fn publish_rank_changes(&self, rank_changes: Vec<RankHistory>) {
+        for change in rank_changes {
+            self.event_bus.publish(events::rank_changed(change));
+        }
+    }
+
+    /// Refreshes the ranks of all users after the ranks themselves changed
+    fn refresh_all_ranks(&self, excluded_rank_ids: &[i32], conn: &PgConnection) -> QueryResult<()> {
+        let rank_changes = ranks::refresh_ranks(None, excluded_rank_ids, conn)?;
+        self.publish_rank_changes(rank_changes);
+        Ok(())
+    }
+
+    fn publish_membership_changes(&self, group_id: i32, channel_ids: &[String], added: bool) {
+        for channel_id in channel_ids {
+            self.event_bus.publish(events::group_membership_changed(channel_id, group_id, added));
+        }
+    }
+
+    fn publish_removed_memberships(&self, memberships: &[GroupUser]) {
+        for membership in memberships {
+            self.event_bus.publish(events::group_membership_changed(&membership.channel_id, membership.group_id, false));
+        }
+    }
 }
 
 #[tonic::async_trait]
 impl UserService for UserServer {
+    type SubscribeEventsStream = ReceiverStream<Result<UserEvent, Status>>;
+
     async fn get_user_by_id(
         &self,
         request: tonic::Request<String>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
         let user_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let potential_user = User::get_from_database(&user_id, &conn);
-
-        match potential_user {
-            Some(user) => {
-                let bpp_user = user.to_userservice_user(&conn);
-                return Ok(tonic::Response::new(bpp_user));
-            }
-            None => Err(tonic::Status::not_found("User not found")),
-        }
+        let conn = self.connection()?;
+        let user = ServiceError::not_found(User::get_existing(&user_id, &conn), "User not found")?;
+        let bpp_user = user.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(bpp_user));
     }
 
     async fn filter_users(
@@ -170,68 +489,42 @@ impl UserService for UserServer {
         request: tonic::Request<userservice::BppUserFilters>,
     ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
         let filter_request = request.into_inner();
-        let filters = &filter_request.filters;
-        let conn = self.database_pool.get().unwrap();
-
-        use schema::bpp_users::dsl::*;
-        let mut query = bpp_users.into_boxed();
-        for filter in filters {
-            let inner_filter = filter.filter.as_ref().unwrap();
-            match inner_filter {
-                userservice::bpp_user_filter::Filter::ChannelId(filter_channel_id) => {
-                    query = query.filter(channel_id.eq(filter_channel_id));
-                }
-                userservice::bpp_user_filter::Filter::Name(filter_name) => {
-                    query = query.filter(display_name.eq(filter_name));
-                }
-                userservice::bpp_user_filter::Filter::Hours(filter_hours) => {
-                    query = query.filter(hours_seconds.eq(filter_hours));
-                }
-                userservice::bpp_user_filter::Filter::Money(filter_money) => {
-                    query = query.filter(money.eq(filter_money));
-                }
-            }
-        }
-
-        match filter_request.sorting() {
-            userservice::bpp_user_filters::SortingFields::HoursAsc => {
-                query = query.order_by(hours_seconds.asc());
-            }
-            userservice::bpp_user_filters::SortingFields::HoursDesc => {
-                query = query.order_by(hours_seconds.desc());
-            }
-            userservice::bpp_user_filters::SortingFields::MoneyAsc => {
-                query = query.order_by(money.asc());
-            }
-            userservice::bpp_user_filters::SortingFields::MoneyDesc => {
-                query = query.order_by(money.desc());
-            }
-            userservice::bpp_user_filters::SortingFields::Default => {}
-        }
-        let users = match query.load::<User>(&conn) {
-            Ok(users) => users,
-            Err(e) => {
-                error!("{}", e);
-                return Err(tonic::Status::internal("Failed to load users"));
-            }
-        };
-        let users: Vec<BppUser> = users
-            .into_iter()
-            .map(|user| user.to_userservice_user(&conn))
-            .collect();
-        let count = users.len() as i32;
-
-        return Ok(tonic::Response::new(userservice::BppUsers { users, count }));
+        // Clients written before paging don't ask for a page size, so they get the largest page instead of the default.
+        // Beyond that they're truncated like any page, which count and next_page_token tell them.
+        let page = Page::with_default_size(filter_request.page_size, &filter_request.page_token, MAX_PAGE_SIZE)?;
+        let conn = self.connection()?;
+
+        let total = filters::filtered_users(&filter_request)?
+            .count()
+            .get_result::<i64>(&conn)
+            .map_err(ServiceError::from)?;
+        let query = filters::sorted_users(filters::filtered_users(&filter_request)?, &filter_request)?;
+        let users = query
+            .limit(page.limit)
+            .offset(page.offset)
+            .load::<User>(&conn)
+            .map_err(ServiceError::from)?;
+
+        let snapshot = self.cached_snapshot(&conn)?;
+        let users = User::to_userservice_users(&users, &snapshot, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppUsers {
+            users,
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
     }
 
     async fn update_user(
         &self,
         request: tonic::Request<userservice::BppUser>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let user = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_user: User = (&user).into();
-        db_user.save_to_database(&conn).unwrap();
+        let conn = self.connection()?;
+        let db_user = User::try_from(&user)?;
+        let changes = save_user_with_ledger(&db_user, &actor, "UpdateUser", &conn)?;
+        self.event_bus.publish_all(changes);
         return Ok(tonic::Response::new(user));
     }
 
@@ -239,11 +532,13 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppUsers>,
     ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let users = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         for user in &users.users {
-            let db_user: User = user.into();
-            db_user.save_to_database(&conn).unwrap();
+            let db_user = User::try_from(user)?;
+            let changes = save_user_with_ledger(&db_user, &actor, "UpdateUsers", &conn)?;
+            self.event_bus.publish_all(changes);
         }
         return Ok(tonic::Response::new(users));
     }
@@ -252,12 +547,10 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<String>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let user_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users::dsl::*;
-        diesel::delete(bpp_users.filter(channel_id.eq(user_id)))
-            .execute(&conn)
-            .unwrap();
+        let conn = self.connection()?;
+        soft_delete_users(&[user_id], &actor, "DeleteUser", &conn)?;
         return Ok(tonic::Response::new(()));
     }
 
@@ -265,70 +558,145 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppUserIds>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let user_ids = request.into_inner().users;
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users::dsl::*;
-        diesel::delete(bpp_users.filter(channel_id.eq_any(user_ids)))
-            .execute(&conn)
-            .unwrap();
+        let conn = self.connection()?;
+        soft_delete_users(&user_ids, &actor, "DeleteUsers", &conn)?;
         return Ok(tonic::Response::new(()));
     }
 
+    async fn restore_user(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let user_id = request.into_inner();
+        let conn = self.connection()?;
+        let user = conn.transaction::<_, ServiceError, _>(|| {
+            let previous = User::get_for_update(&user_id, &conn)?;
+            let user = ServiceError::not_found(User::restore(&user_id, &conn), "Deleted user not found")?;
+            audit::record(
+                &actor,
+                "RestoreUser",
+                Target::User(user_id.clone()),
+                previous.as_ref().map(audit::user_value),
+                Some(audit::user_value(&user)),
+                &conn,
+            )?;
+            Ok(user)
+        })?;
+        let bpp_user = user.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(bpp_user));
+    }
+
+    async fn list_deleted_users(
+        &self,
+        request: tonic::Request<userservice::DeletedUsersRequest>,
+    ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
+        let deleted_request = request.into_inner();
+        let page = Page::new(deleted_request.page_size, &deleted_request.page_token)?;
+        let conn = self.connection()?;
+
+        let total = User::count_deleted(&conn).map_err(ServiceError::from)?;
+        let users = User::get_deleted(&page, &conn).map_err(ServiceError::from)?;
+        let snapshot = self.cached_snapshot(&conn)?;
+        let users = User::to_userservice_users(&users, &snapshot, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppUsers {
+            users,
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
     async fn create_user(
         &self,
         request: tonic::Request<userservice::BppUser>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let user = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_user: User = (&user).into();
-        db_user.save_to_database(&conn).unwrap();
+        let conn = self.connection()?;
+        let db_user = User::try_from(&user)?;
+        let changes = save_user_with_ledger(&db_user, &actor, "CreateUser", &conn)?;
+        self.event_bus.publish_all(changes);
         return Ok(tonic::Response::new(user));
     }
 
+    async fn export_user_data(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::UserDataExport>, tonic::Status> {
+        let user_id = request.into_inner();
+        let conn = self.connection()?;
+        let data = ServiceError::not_found(privacy::export_user_data(&user_id, &conn), "User not found")?;
+        return Ok(tonic::Response::new(userservice::UserDataExport {
+            channel_id: user_id,
+            data,
+        }));
+    }
+
+    async fn erase_user(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let user_id = request.into_inner();
+        let conn = self.connection()?;
+        ServiceError::not_found(privacy::erase_user(&user_id, &actor, &conn), "User not found")?;
+        info!("Erased the data of a user");
+        return Ok(tonic::Response::new(()));
+    }
+
+    async fn query_audit_log(
+        &self,
+        request: tonic::Request<userservice::AuditLogRequest>,
+    ) -> Result<tonic::Response<userservice::AuditEntries>, tonic::Status> {
+        let audit_request = request.into_inner();
+        let page = Page::new(audit_request.page_size, &audit_request.page_token)?;
+        let filter = audit::AuditFilter {
+            since: audit_request.since.as_ref().map(timestamp_to_naive).transpose()?,
+            until: audit_request.until.as_ref().map(timestamp_to_naive).transpose()?,
+            actor: audit_request.actor,
+            rpc: audit_request.rpc,
+            target_type: audit_request.target_type,
+            target_id: audit_request.target_id,
+        };
+        let conn = self.connection()?;
+
+        let (total, entries) = audit::get_entries(&filter, &page, &conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(userservice::AuditEntries {
+            entries: entries.into_iter().map(|entry| entry.into()).collect(),
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
     async fn user_has_permission(
         &self,
         request: tonic::Request<userservice::UserPermissionCheck>,
     ) -> Result<tonic::Response<bool>, tonic::Status> {
         let check = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-
-        let user_id = check.channel_id;
-        let permission = check.permission;
-        let mut has_permission = check.granted_default;
-
-        let mut user_groups = Group::get_groups_for_user(user_id.clone(), &conn);
-        user_groups.sort();
-        for group in user_groups {
-            let group_permissions =
-                GroupPermission::get_permissions_for_group(group.group_id, &conn);
-            let searched_permission = group_permissions
-                .iter()
-                .find(|group_permission| group_permission.permission == permission);
-            if let Some(searched_permission) = searched_permission {
-                has_permission = searched_permission.granted;
-            }
-        }
-
-        let user_permissions = UserPermission::get_permissions_for_user(user_id, &conn);
-        let searched_permission = user_permissions
-            .iter()
-            .find(|perm| perm.permission == permission);
-        if let Some(searched_permission) = searched_permission {
-            has_permission = searched_permission.granted;
-        }
+        let conn = self.connection()?;
+
+        let snapshot = self.cached_snapshot(&conn)?;
+        let grants = UserGrants::load(&[check.channel_id.as_str()], &conn).map_err(ServiceError::from)?;
+        let has_permission = permissions::user_has_permission(
+            &snapshot,
+            &grants,
+            &check.channel_id,
+            &check.permission,
+            check.granted_default,
+        );
 
         return Ok(tonic::Response::new(has_permission));
     }
 
     async fn get_group(&self, request: Request<i32>) -> Result<Response<userservice::BppGroup>, Status> {
         let group_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let group = Group::get_from_database(&group_id, &conn);
-        if group.is_none() {
-            return Err(Status::not_found("Group not found"));
-        }
-        let group = group.unwrap();
-        let group_permissions = GroupPermission::get_permissions_for_group(group_id, &conn);
+        let conn = self.connection()?;
+        let group = ServiceError::not_found(Group::get_from_database(&group_id, &conn), "Group not found")?;
+        let group_permissions =
+            GroupPermission::get_permissions_for_group(group_id, &conn).map_err(ServiceError::from)?;
         let group_permissions: Vec<userservice::Permission> = group_permissions
             .into_iter()
             .map(|group_permission| userservice::Permission {
@@ -350,17 +718,16 @@ impl UserService for UserServer {
         &self,
         _: tonic::Request<()>,
     ) -> Result<tonic::Response<userservice::BppGroups>, tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         use schema::bpp_groups::dsl::*;
         let groups = bpp_groups
             .order(group_sorting.desc())
             .load::<Group>(&conn)
-            .unwrap();
-        let groups: Vec<BppGroup> = groups
+            .map_err(ServiceError::from)?;
+        let groups = groups
             .into_iter()
             .map(|group| {
-                let group_permissions =
-                    GroupPermission::get_permissions_for_group(group.group_id, &conn);
+                let group_permissions = GroupPermission::get_permissions_for_group(group.group_id, &conn)?;
                 let group_permissions: Vec<userservice::Permission> = group_permissions
                     .into_iter()
                     .map(|p| userservice::Permission {
@@ -369,15 +736,16 @@ impl UserService for UserServer {
                     })
                     .collect();
 
-                BppGroup {
+                Ok(BppGroup {
                     group_id: group.group_id,
                     group_name: group.group_name,
                     permissions: group_permissions,
                     bonus_payout: group.bonus_payout,
                     group_sorting: group.group_sorting,
-                }
+                })
             })
-            .collect();
+            .collect::<QueryResult<Vec<BppGroup>>>()
+            .map_err(ServiceError::from)?;
         let count = groups.len() as i32;
         return Ok(tonic::Response::new(userservice::BppGroups {
             groups,
@@ -389,10 +757,12 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppGroup>,
     ) -> Result<tonic::Response<userservice::BppGroup>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let group = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         let db_group: Group = (&group).into();
-        db_group.save_to_database(&conn).unwrap();
+        save_group(&db_group, &actor, "UpdateGroup", &conn)?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(group));
     }
 
@@ -400,25 +770,32 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppGroups>,
     ) -> Result<tonic::Response<userservice::BppGroups>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let groups = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        for group in &groups.groups {
-            let db_group: Group = group.into();
-            db_group.save_to_database(&conn).unwrap();
-        }
+        let conn = self.connection()?;
+        // A missing group rolls back the groups saved before it
+        conn.transaction::<_, ServiceError, _>(|| {
+            for group in &groups.groups {
+                let db_group: Group = group.into();
+                save_group(&db_group, &actor, "UpdateGroups", &conn)?;
+            }
+            Ok(())
+        })?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(groups));
     }
 
     async fn delete_group(
         &self,
-        request: tonic::Request<i32>,
+        request: tonic::Request<userservice::DeleteGroupRequest>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups::dsl::*;
-        diesel::delete(bpp_groups.filter(group_id.eq(id)))
-            .execute(&conn)
-            .unwrap();
+        let actor = actor_from_request(&request);
+        let delete_request = request.into_inner();
+        let conn = self.connection()?;
+        let memberships =
+            delete_groups(&[delete_request.group_id], delete_request.only_if_empty, &actor, "DeleteGroup", &conn)?;
+        self.publish_removed_memberships(&memberships);
+        self.cache.invalidate();
         return Ok(tonic::Response::new(()));
     }
 
@@ -426,12 +803,13 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppGroupIds>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let group_ids = request.into_inner().groups;
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups::dsl::*;
-        diesel::delete(bpp_groups.filter(group_id.eq_any(group_ids)))
-            .execute(&conn)
-            .unwrap();
+        let actor = actor_from_request(&request);
+        let delete_request = request.into_inner();
+        let conn = self.connection()?;
+        let memberships =
+            delete_groups(&delete_request.groups, delete_request.only_if_empty, &actor, "DeleteGroups", &conn)?;
+        self.publish_removed_memberships(&memberships);
+        self.cache.invalidate();
         return Ok(tonic::Response::new(()));
     }
 
@@ -439,13 +817,21 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::CreateBppGroup>,
     ) -> Result<tonic::Response<userservice::BppGroup>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let create_group = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         let db_group: InsertGroup = create_group.into();
-        let created_group = db_group.save_to_database(&conn).unwrap();
+        let created_group = conn
+            .transaction::<_, diesel::result::Error, _>(|| {
+                let created_group = db_group.save_to_database(&conn)?;
+                let after = Some(audit::group_value(&created_group));
+                audit::record(&actor, "CreateGroup", Target::Group(created_group.group_id), None, after, &conn)?;
+                Ok(created_group)
+            })
+            .map_err(ServiceError::from)?;
 
         let group_permissions =
-            GroupPermission::get_permissions_for_group(created_group.group_id, &conn);
+            GroupPermission::get_permissions_for_group(created_group.group_id, &conn).map_err(ServiceError::from)?;
         let group_permissions: Vec<userservice::Permission> = group_permissions
             .into_iter()
             .map(|p| userservice::Permission {
@@ -461,18 +847,14 @@ impl UserService for UserServer {
             bonus_payout: created_group.bonus_payout,
             group_sorting: created_group.group_sorting,
         };
+        self.cache.invalidate();
         return Ok(tonic::Response::new(group));
     }
 
     async fn get_rank(&self, request:tonic::Request<i32>) ->Result<tonic::Response<userservice::BppRank>,tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         let rank = request.into_inner();
-        let rank = Rank::get_from_database(&rank, &conn);
-
-        if rank.is_none() {
-            return Err(Status::not_found("Rank not found"));
-        }
-        let rank = rank.unwrap();
+        let rank = ServiceError::not_found(Rank::get_from_database(&rank, &conn), "Rank not found")?;
 
         let hour_requirement = prost_types::Duration {
             seconds: rank.hour_requirement_seconds,
@@ -491,12 +873,12 @@ impl UserService for UserServer {
         &self,
         _: tonic::Request<()>,
     ) -> Result<tonic::Response<userservice::BppRanks>, tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         use schema::bpp_ranks::dsl::*;
         let ranks = bpp_ranks
             .order(rank_sorting.desc())
             .load::<Rank>(&conn)
-            .unwrap();
+            .map_err(ServiceError::from)?;
         let ranks: Vec<userservice::BppRank> = ranks
             .into_iter()
             .map(|rank| {
@@ -521,10 +903,13 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppRank>,
     ) -> Result<tonic::Response<userservice::BppRank>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let rank = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_rank: Rank = (&rank).into();
-        db_rank.save_to_database(&conn).unwrap();
+        let conn = self.connection()?;
+        let db_rank = Rank::try_from(&rank)?;
+        save_rank(&db_rank, &actor, "UpdateRank", &conn)?;
+        self.refresh_all_ranks(&[], &conn).map_err(ServiceError::from)?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(rank));
     }
 
@@ -532,12 +917,19 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppRanks>,
     ) -> Result<tonic::Response<userservice::BppRanks>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let ranks = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        for rank in &ranks.ranks {
-            let db_rank: Rank = rank.into();
-            db_rank.save_to_database(&conn).unwrap();
-        }
+        let conn = self.connection()?;
+        // A missing rank rolls back the ranks saved before it
+        conn.transaction::<_, ServiceError, _>(|| {
+            for rank in &ranks.ranks {
+                let db_rank = Rank::try_from(rank)?;
+                save_rank(&db_rank, &actor, "UpdateRanks", &conn)?;
+            }
+            Ok(())
+        })?;
+        self.refresh_all_ranks(&[], &conn).map_err(ServiceError::from)?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(ranks));
     }
 
@@ -545,12 +937,22 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<i32>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         use schema::bpp_ranks::dsl::*;
-        diesel::delete(bpp_ranks.filter(rank_id.eq(id)))
-            .execute(&conn)
-            .unwrap();
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            // Move the users to their next rank first, so the history still knows the name of the deleted rank
+            self.refresh_all_ranks(&[id], &conn)?;
+            let deleted = diesel::delete(bpp_ranks.filter(rank_id.eq(id))).get_results::<Rank>(&conn)?;
+            for rank in &deleted {
+                let before = Some(audit::rank_value(rank));
+                audit::record(&actor, "DeleteRank", Target::Rank(rank.rank_id), before, None, &conn)?;
+            }
+            Ok(())
+        })
+        .map_err(ServiceError::from)?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(()));
     }
 
@@ -558,12 +960,21 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::BppRankIds>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let rank_ids = request.into_inner().ranks;
-        let conn = self.database_pool.get().unwrap();
+        let conn = self.connection()?;
         use schema::bpp_ranks::dsl::*;
-        diesel::delete(bpp_ranks.filter(rank_id.eq_any(rank_ids)))
-            .execute(&conn)
-            .unwrap();
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            self.refresh_all_ranks(&rank_ids, &conn)?;
+            let deleted = diesel::delete(bpp_ranks.filter(rank_id.eq_any(&rank_ids))).get_results::<Rank>(&conn)?;
+            for rank in &deleted {
+                let before = Some(audit::rank_value(rank));
+                audit::record(&actor, "DeleteRanks", Target::Rank(rank.rank_id), before, None, &conn)?;
+            }
+            Ok(())
+        })
+        .map_err(ServiceError::from)?;
+        self.cache.invalidate();
         return Ok(tonic::Response::new(()));
     }
 
@@ -571,10 +982,19 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::CreateBppRank>,
     ) -> Result<tonic::Response<userservice::BppRank>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let create_rank = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_rank: InsertRank = create_rank.into();
-        let created_rank = db_rank.save_to_database(&conn).unwrap();
+        let conn = self.connection()?;
+        let db_rank = InsertRank::try_from(create_rank)?;
+        let created_rank = conn
+            .transaction::<_, diesel::result::Error, _>(|| {
+                let created_rank = db_rank.save_to_database(&conn)?;
+                let after = Some(audit::rank_value(&created_rank));
+                audit::record(&actor, "CreateRank", Target::Rank(created_rank.rank_id), None, after, &conn)?;
+                Ok(created_rank)
+            })
+            .map_err(ServiceError::from)?;
+        self.refresh_all_ranks(&[], &conn).map_err(ServiceError::from)?;
         let hour_requirement = prost_types::Duration {
             seconds: created_rank.hour_requirement_seconds,
             nanos: created_rank.hour_requirement_nanos,
@@ -585,6 +1005,7 @@ impl UserService for UserServer {
             rank_sorting: created_rank.rank_sorting,
             hour_requirement: Some(hour_requirement),
         };
+        self.cache.invalidate();
         return Ok(tonic::Response::new(rank));
     }
 
@@ -592,18 +1013,23 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::UserPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let granted_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users_permissions::dsl::*;
+        let conn = self.connection()?;
         let db_permission = models::UserPermission {
             channel_id: granted_permission.channel_id,
             permission: granted_permission.permission,
             granted: true
         };
-        diesel::insert_into(bpp_users_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
+        if !set_user_permission(&db_permission, &actor, "UserGrantPermission", &conn).map_err(ServiceError::from)? {
+            return Ok(tonic::Response::new(()));
+        }
+        self.event_bus.publish(events::permission_changed(
+            &db_permission.channel_id,
+            0,
+            db_permission.permission,
+            db_permission.granted,
+        ));
         return Ok(tonic::Response::new(()));
     }
 
@@ -611,18 +1037,23 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::UserPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let revoked_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users_permissions::dsl::*;
+        let conn = self.connection()?;
         let db_permission = models::UserPermission {
             channel_id: revoked_permission.channel_id,
             permission: revoked_permission.permission,
             granted: false
         };
-        diesel::insert_into(bpp_users_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
+        if !set_user_permission(&db_permission, &actor, "UserRevokePermission", &conn).map_err(ServiceError::from)? {
+            return Ok(tonic::Response::new(()));
+        }
+        self.event_bus.publish(events::permission_changed(
+            &db_permission.channel_id,
+            0,
+            db_permission.permission,
+            db_permission.granted,
+        ));
         return Ok(tonic::Response::new(()));
     }
 
@@ -630,18 +1061,24 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::GroupPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let granted_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups_permissions::dsl::*;
+        let conn = self.connection()?;
         let db_permission = models::GroupPermission {
             group_id: granted_permission.group_id,
             permission: granted_permission.permission,
             granted: true
         };
-        diesel::insert_into(bpp_groups_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
+        if !set_group_permission(&db_permission, &actor, "GroupGrantPermission", &conn).map_err(ServiceError::from)? {
+            return Ok(tonic::Response::new(()));
+        }
+        self.event_bus.publish(events::permission_changed(
+            "",
+            db_permission.group_id,
+            db_permission.permission,
+            db_permission.granted,
+        ));
+        self.cache.invalidate();
         return Ok(tonic::Response::new(()));
     }
 
@@ -649,20 +1086,559 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<userservice::GroupPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
         let revoked_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups_permissions::dsl::*;
+        let conn = self.connection()?;
         let db_permission = models::GroupPermission {
             group_id: revoked_permission.group_id,
             permission: revoked_permission.permission,
             granted: false
         };
-        diesel::insert_into(bpp_groups_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
+        if !set_group_permission(&db_permission, &actor, "GroupRevokePermission", &conn).map_err(ServiceError::from)? {
+            return Ok(tonic::Response::new(()));
+        }
+        self.event_bus.publish(events::permission_changed(
+            "",
+            db_permission.group_id,
+            db_permission.permission,
+            db_permission.granted,
+        ));
+        self.cache.invalidate();
+        return Ok(tonic::Response::new(()));
+    }
+
+    async fn add_user_to_group(
+        &self,
+        request: tonic::Request<userservice::GroupMembership>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let membership = request.into_inner();
+        let conn = self.connection()?;
+        let channel_ids = vec![membership.channel_id];
+        check_membership_targets(membership.group_id, &channel_ids, &conn)?;
+        let added = change_memberships(membership.group_id, &channel_ids, true, &actor, "AddUserToGroup", &conn)
+            .map_err(ServiceError::from)?;
+        self.publish_membership_changes(membership.group_id, &added, true);
+        return Ok(tonic::Response::new(()));
+    }
+
+    async fn remove_user_from_group(
+        &self,
+        request: tonic::Request<userservice::GroupMembership>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let membership = request.into_inner();
+        let conn = self.connection()?;
+        let channel_ids = vec![membership.channel_id];
+        check_membership_targets(membership.group_id, &channel_ids, &conn)?;
+        let removed = change_memberships(membership.group_id, &channel_ids, false, &actor, "RemoveUserFromGroup", &conn)
+            .map_err(ServiceError::from)?;
+        if removed.is_empty() {
+            return Err(Status::not_found("User is not a member of this group"));
+        }
+        self.publish_membership_changes(membership.group_id, &removed, false);
+        return Ok(tonic::Response::new(()));
+    }
+
+    async fn add_users_to_group(
+        &self,
+        request: tonic::Request<userservice::GroupMemberships>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let memberships = request.into_inner();
+        let conn = self.connection()?;
+        check_membership_targets(memberships.group_id, &memberships.channel_ids, &conn)?;
+        let added =
+            change_memberships(memberships.group_id, &memberships.channel_ids, true, &actor, "AddUsersToGroup", &conn)
+                .map_err(ServiceError::from)?;
+        self.publish_membership_changes(memberships.group_id, &added, true);
+        return Ok(tonic::Response::new(()));
+    }
+
+    async fn remove_users_from_group(
+        &self,
+        request: tonic::Request<userservice::GroupMemberships>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let memberships = request.into_inner();
+        let conn = self.connection()?;
+        check_membership_targets(memberships.group_id, &memberships.channel_ids, &conn)?;
+        let removed = change_memberships(
+            memberships.group_id,
+            &memberships.channel_ids,
+            false,
+            &actor,
+            "RemoveUsersFromGroup",
+            &conn,
+        )
+        .map_err(ServiceError::from)?;
+        self.publish_membership_changes(memberships.group_id, &removed, false);
         return Ok(tonic::Response::new(()));
     }
+
+    async fn list_group_members(
+        &self,
+        request: tonic::Request<userservice::GroupMembersRequest>,
+    ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
+        let members_request = request.into_inner();
+        let page = Page::new(members_request.page_size, &members_request.page_token)?;
+        let conn = self.connection()?;
+        ServiceError::not_found(Group::get_from_database(&members_request.group_id, &conn), "Group not found")?;
+
+        let total = GroupUser::count_members(members_request.group_id, &conn).map_err(ServiceError::from)?;
+        let users = GroupUser::get_members(members_request.group_id, &page, &conn).map_err(ServiceError::from)?;
+
+        let snapshot = self.cached_snapshot(&conn)?;
+        let users = User::to_userservice_users(&users, &snapshot, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppUsers {
+            users,
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
+    async fn explain_permission(
+        &self,
+        request: tonic::Request<userservice::UserPermissionCheck>,
+    ) -> Result<tonic::Response<userservice::PermissionExplanation>, tonic::Status> {
+        let check = request.into_inner();
+        let conn = self.connection()?;
+
+        let snapshot = self.cached_snapshot(&conn)?;
+        let grants = UserGrants::load(&[check.channel_id.as_str()], &conn).map_err(ServiceError::from)?;
+        let trace = permissions::explain_user_permission(
+            &snapshot,
+            &grants,
+            &check.channel_id,
+            &check.permission,
+            check.granted_default,
+        );
+        let explanation = userservice::PermissionExplanation {
+            channel_id: check.channel_id,
+            permission: check.permission,
+            decisions: trace.steps.into_iter().map(|step| step.into()).collect(),
+            granted: trace.granted,
+        };
+
+        return Ok(tonic::Response::new(explanation));
+    }
+
+    async fn get_transactions(
+        &self,
+        request: tonic::Request<userservice::TransactionsRequest>,
+    ) -> Result<tonic::Response<userservice::BppTransactions>, tonic::Status> {
+        let transactions_request = request.into_inner();
+        let page = Page::new(transactions_request.page_size, &transactions_request.page_token)?;
+        let since = transactions_request.since.as_ref().map(timestamp_to_naive).transpose()?;
+        let until = transactions_request.until.as_ref().map(timestamp_to_naive).transpose()?;
+        let conn = self.connection()?;
+
+        let (total, transactions) =
+            ledger::get_transactions(&transactions_request.channel_id, since, until, &page, &conn)
+                .map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppTransactions {
+            transactions: transactions.into_iter().map(|transaction| transaction.into()).collect(),
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
+    async fn get_ledger_balance(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::LedgerBalance>, tonic::Status> {
+        let user_id = request.into_inner();
+        let conn = self.connection()?;
+        let user = ServiceError::not_found(User::get_existing(&user_id, &conn), "User not found")?;
+
+        let ledger_balance = ledger::ledger_balance(&user_id, &conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(userservice::LedgerBalance {
+            channel_id: user_id,
+            balance: money::to_minor_units(&user.money),
+            ledger_balance: money::to_minor_units(&ledger_balance),
+            consistent: user.money == ledger_balance,
+        }));
+    }
+
+    async fn transfer_money(
+        &self,
+        request: tonic::Request<userservice::MoneyTransfer>,
+    ) -> Result<tonic::Response<userservice::MoneyTransferResult>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let transfer = request.into_inner();
+        if transfer.amount <= 0 {
+            return Err(Status::invalid_argument("Amount must be positive"));
+        }
+        if transfer.from_channel_id == transfer.to_channel_id {
+            return Err(Status::failed_precondition("Users can't transfer money to themselves"));
+        }
+
+        let settings = Settings::new().map_err(ServiceError::from)?;
+        let fee_minor_units = match transfer.amount.checked_mul(settings.transfer_fee_percent as i64) {
+            Some(fee) => fee / 100,
+            None => return Err(Status::invalid_argument("Amount is too large")),
+        };
+        let amount = money::from_minor_units(transfer.amount);
+        let fee = money::from_minor_units(fee_minor_units);
+
+        let conn = self.connection()?;
+        let (sender, recipient) = match ledger::transfer(
+            &transfer.from_channel_id,
+            &transfer.to_channel_id,
+            &amount,
+            &fee,
+            &actor,
+            "TransferMoney",
+            &conn,
+        ) {
+            Ok(users) => users,
+            Err(ledger::TransferError::UserNotFound(channel_id)) => {
+                return Err(Status::not_found(format!("User {} not found", channel_id)));
+            }
+            Err(ledger::TransferError::BalanceOutOfRange(channel_id)) => {
+                return Err(Status::failed_precondition(format!(
+                    "The balance of {} would exceed the supported range",
+                    channel_id
+                )));
+            }
+            Err(ledger::TransferError::InsufficientFunds) => {
+                return Err(Status::failed_precondition("Insufficient funds"));
+            }
+            Err(ledger::TransferError::Database(e)) => return Err(ServiceError::from(e).into()),
+        };
+
+        let sent = -(&amount + &fee);
+        let bpp_sender = sender.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        let bpp_recipient = recipient.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        self.event_bus.publish_all(vec![
+            events::user_updated(bpp_sender.clone(), vec!["money".to_string(), "money_minor_units".to_string()]),
+            events::money_adjusted(&sender.channel_id, &sent, &sender.money, TransactionReason::Transfer),
+            events::user_updated(bpp_recipient.clone(), vec!["money".to_string(), "money_minor_units".to_string()]),
+            events::money_adjusted(&recipient.channel_id, &amount, &recipient.money, TransactionReason::Transfer),
+        ]);
+
+        return Ok(tonic::Response::new(userservice::MoneyTransferResult {
+            sender: Some(bpp_sender),
+            recipient: Some(bpp_recipient),
+            fee: fee_minor_units,
+        }));
+    }
+
+    async fn adjust_money(
+        &self,
+        request: tonic::Request<userservice::MoneyAdjustment>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let adjustment = request.into_inner();
+        let conn = self.connection()?;
+        let delta = money::from_minor_units(adjustment.delta);
+
+        let (previous, user) =
+            ledger::adjust(&adjustment.channel_id, &delta, adjustment.floor_at_zero, &actor, "AdjustMoney", &conn)?;
+        let changes = user_change_events(Some(&previous), &user, TransactionReason::Adjustment, &conn)
+            .map_err(ServiceError::from)?;
+        self.event_bus.publish_all(changes);
+        let bpp_user = user.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(bpp_user));
+    }
+
+    async fn adjust_hours(
+        &self,
+        request: tonic::Request<userservice::HoursAdjustment>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let adjustment = request.into_inner();
+        let conn = self.connection()?;
+        let delta = adjustment.delta.clone().unwrap_or_default();
+        if delta.nanos != 0 {
+            return Err(Status::invalid_argument("Hours can only be adjusted by whole seconds"));
+        }
+
+        let (previous, user, rank_changes) = conn.transaction::<_, ServiceError, _>(|| {
+            let previous = match User::get_for_update(&adjustment.channel_id, &conn)? {
+                Some(previous) if !previous.is_deleted() => previous,
+                _ => return Err(ServiceError::NotFound("User not found".to_string())),
+            };
+            if previous.hours_seconds.checked_add(delta.seconds).is_none() {
+                return Err(ServiceError::FailedPrecondition(format!(
+                    "The hours of {} would exceed the supported range",
+                    adjustment.channel_id
+                )));
+            }
+            let user = User::adjust_hours(&adjustment.channel_id, delta.seconds, adjustment.floor_at_zero, &conn)?;
+            audit::record(
+                &actor,
+                "AdjustHours",
+                Target::User(user.channel_id.clone()),
+                Some(audit::hours_value(previous.hours_seconds)),
+                Some(audit::hours_value(user.hours_seconds)),
+                &conn,
+            )?;
+            let rank_changes = ranks::refresh_ranks(Some(&adjustment.channel_id), &[], &conn)?;
+            Ok((previous, user, rank_changes))
+        })?;
+
+        let changes = user_change_events(Some(&previous), &user, TransactionReason::Adjustment, &conn)
+            .map_err(ServiceError::from)?;
+        self.event_bus.publish_all(changes);
+        self.publish_rank_changes(rank_changes);
+        let bpp_user = user.to_userservice_user(&conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(bpp_user));
+    }
+
+    async fn subscribe_events(
+        &self,
+        request: tonic::Request<userservice::EventSubscription>,
+    ) -> Result<tonic::Response<Self::SubscribeEventsStream>, tonic::Status> {
+        let subscription = request.into_inner();
+        return Ok(tonic::Response::new(self.event_bus.subscribe(subscription)));
+    }
+
+    async fn get_leaderboard(
+        &self,
+        request: tonic::Request<userservice::LeaderboardRequest>,
+    ) -> Result<tonic::Response<userservice::Leaderboard>, tonic::Status> {
+        let leaderboard_request = request.into_inner();
+        let stat = match userservice::LeaderboardStat::from_i32(leaderboard_request.stat) {
+            Some(stat) => stat,
+            None => return Err(Status::invalid_argument("Unknown leaderboard stat")),
+        };
+        let limit = leaderboard::leaderboard_size(leaderboard_request.limit)?;
+        let conn = self.connection()?;
+
+        let (total, rows) = leaderboard::top_users(stat, limit, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::Leaderboard {
+            stat: stat as i32,
+            total,
+            entries: rows.into_iter().map(|row| row.into()).collect(),
+        }));
+    }
+
+    async fn get_user_position(
+        &self,
+        request: tonic::Request<userservice::UserPositionRequest>,
+    ) -> Result<tonic::Response<userservice::UserPosition>, tonic::Status> {
+        let position_request = request.into_inner();
+        let stat = match userservice::LeaderboardStat::from_i32(position_request.stat) {
+            Some(stat) => stat,
+            None => return Err(Status::invalid_argument("Unknown leaderboard stat")),
+        };
+        if position_request.neighbours < 0 {
+            return Err(Status::invalid_argument("Neighbours must not be negative"));
+        }
+        let neighbours = (position_request.neighbours as i64).min(leaderboard::MAX_NEIGHBOURS);
+        let conn = self.connection()?;
+
+        let (total, mut rows) =
+            leaderboard::user_with_neighbours(stat, &position_request.channel_id, neighbours, &conn)
+                .map_err(ServiceError::from)?;
+        let index = match rows.iter().position(|row| row.channel_id == position_request.channel_id) {
+            Some(index) => index,
+            None => return Err(Status::not_found("User not found")),
+        };
+
+        let below = rows.split_off(index + 1);
+        let entry = rows.pop();
+        return Ok(tonic::Response::new(userservice::UserPosition {
+            stat: stat as i32,
+            entry: entry.map(|row| row.into()),
+            above: rows.into_iter().map(|row| row.into()).collect(),
+            below: below.into_iter().map(|row| row.into()).collect(),
+            total,
+        }));
+    }
+
+    async fn get_rank_history(
+        &self,
+        request: tonic::Request<userservice::RankHistoryRequest>,
+    ) -> Result<tonic::Response<userservice::RankChanges>, tonic::Status> {
+        let history_request = request.into_inner();
+        let page = Page::new(history_request.page_size, &history_request.page_token)?;
+        let conn = self.connection()?;
+
+        let (total, changes) =
+            ranks::get_rank_history(&history_request.channel_id, &page, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::RankChanges {
+            changes: changes.into_iter().map(|change| change.into()).collect(),
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
+    async fn get_ingestion_status(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::IngestionStatus>, tonic::Status> {
+        let status = self.ingestion.to_userservice_status();
+        return Ok(tonic::Response::new(status));
+    }
+
+    async fn start_broadcast(
+        &self,
+        request: tonic::Request<userservice::StartBroadcastRequest>,
+    ) -> Result<tonic::Response<userservice::BppBroadcast>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let broadcast_request = request.into_inner();
+        let conn = self.connection()?;
+        let now = Utc::now().naive_utc();
+
+        let broadcast = conn.transaction::<_, ServiceError, _>(|| {
+            let broadcast = sessions::start_broadcast(broadcast_request.title, broadcast_request.video_id, now, &conn)?;
+            record_broadcast_changes(None, broadcast.as_ref(), &actor, "StartBroadcast", &conn)?;
+            Ok(broadcast)
+        })?;
+        return match broadcast {
+            Some(broadcast) => Ok(tonic::Response::new(broadcast.into())),
+            None => Err(Status::failed_precondition("Another broadcast is still running")),
+        };
+    }
+
+    async fn end_broadcast(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::BppBroadcast>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let conn = self.connection()?;
+        let now = Utc::now().naive_utc();
+
+        let broadcast = conn.transaction::<_, ServiceError, _>(|| {
+            let previous = sessions::running_broadcast(&conn)?;
+            let broadcast = sessions::end_broadcast(now, &conn)?;
+            record_broadcast_changes(previous.as_ref(), None, &actor, "EndBroadcast", &conn)?;
+            Ok(broadcast)
+        })?;
+        return match broadcast {
+            Some(broadcast) => Ok(tonic::Response::new(broadcast.into())),
+            None => Err(Status::failed_precondition("No broadcast is running")),
+        };
+    }
+
+    async fn get_broadcasts(
+        &self,
+        request: tonic::Request<userservice::BroadcastsRequest>,
+    ) -> Result<tonic::Response<userservice::BppBroadcasts>, tonic::Status> {
+        let broadcasts_request = request.into_inner();
+        let page = Page::new(broadcasts_request.page_size, &broadcasts_request.page_token)?;
+        let conn = self.connection()?;
+
+        let (total, broadcasts) = sessions::get_broadcasts(&page, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppBroadcasts {
+            broadcasts: broadcasts.into_iter().map(|broadcast| broadcast.into()).collect(),
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
+    async fn set_broadcast_state(
+        &self,
+        request: tonic::Request<userservice::BroadcastStateRequest>,
+    ) -> Result<tonic::Response<userservice::BroadcastStatus>, tonic::Status> {
+        let actor = actor_from_request(&request);
+        let state_request = request.into_inner();
+        let state = match userservice::BroadcastState::from_i32(state_request.state) {
+            Some(state) => BroadcastState::from(state),
+            None => return Err(Status::invalid_argument("Unknown broadcast state")),
+        };
+        let conn = self.connection()?;
+        let now = Utc::now().naive_utc();
+
+        let broadcast = conn.transaction::<_, ServiceError, _>(|| {
+            let previous = sessions::running_broadcast(&conn)?;
+            let broadcast =
+                sessions::set_broadcast_state(state, &state_request.title, &state_request.video_id, now, &conn)?;
+            record_broadcast_changes(previous.as_ref(), broadcast.as_ref(), &actor, "SetBroadcastState", &conn)?;
+            Ok(broadcast)
+        })?;
+        return Ok(tonic::Response::new(userservice::BroadcastStatus {
+            state: state_request.state,
+            broadcast: broadcast.map(|broadcast| broadcast.into()),
+        }));
+    }
+
+    async fn get_broadcast_state(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::BroadcastStatus>, tonic::Status> {
+        let conn = self.connection()?;
+
+        let broadcast = sessions::running_broadcast(&conn).map_err(ServiceError::from)?;
+        return Ok(tonic::Response::new(userservice::BroadcastStatus {
+            state: userservice::BroadcastState::from(sessions::current_state(broadcast.as_ref())) as i32,
+            broadcast: broadcast.map(|broadcast| broadcast.into()),
+        }));
+    }
+
+    async fn get_user_sessions(
+        &self,
+        request: tonic::Request<userservice::UserSessionsRequest>,
+    ) -> Result<tonic::Response<userservice::BppSessions>, tonic::Status> {
+        let sessions_request = request.into_inner();
+        let page = Page::new(sessions_request.page_size, &sessions_request.page_token)?;
+        // Broadcast id 0 stands for all broadcasts
+        let broadcast_id = Some(sessions_request.broadcast_id).filter(|broadcast_id| *broadcast_id != 0);
+        let conn = self.connection()?;
+
+        let (total, user_sessions) = sessions::get_sessions(&sessions_request.channel_id, broadcast_id, &page, &conn)
+            .map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BppSessions {
+            sessions: user_sessions.into_iter().map(|session| session.into()).collect(),
+            count: total as i32,
+            next_page_token: page.next_page_token(total),
+        }));
+    }
+
+    async fn get_watch_time(
+        &self,
+        request: tonic::Request<userservice::WatchTimeRequest>,
+    ) -> Result<tonic::Response<userservice::WatchTime>, tonic::Status> {
+        let watch_time_request = request.into_inner();
+        // Broadcast id 0 stands for all broadcasts
+        let broadcast_id = Some(watch_time_request.broadcast_id).filter(|broadcast_id| *broadcast_id != 0);
+        let conn = self.connection()?;
+
+        let watch_time =
+            sessions::watch_time(&watch_time_request.channel_id, broadcast_id, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::WatchTime {
+            channel_id: watch_time_request.channel_id,
+            broadcast_id: watch_time_request.broadcast_id,
+            watch_time: Some(prost_types::Duration {
+                seconds: watch_time.watched_seconds,
+                nanos: 0,
+            }),
+            message_count: watch_time.message_count,
+            session_count: watch_time.session_count,
+        }));
+    }
+
+    async fn get_broadcast_leaderboard(
+        &self,
+        request: tonic::Request<userservice::BroadcastLeaderboardRequest>,
+    ) -> Result<tonic::Response<userservice::BroadcastLeaderboard>, tonic::Status> {
+        let leaderboard_request = request.into_inner();
+        let limit = leaderboard::leaderboard_size(leaderboard_request.limit)?;
+        let conn = self.connection()?;
+
+        let broadcast = match sessions::get_broadcast(leaderboard_request.broadcast_id, &conn) {
+            Ok(Some(broadcast)) => broadcast,
+            Ok(None) => return Err(Status::not_found("Broadcast not found")),
+            Err(e) => return Err(ServiceError::from(e).into()),
+        };
+        let (total, rows) =
+            sessions::top_watchers(broadcast.broadcast_id, limit, &conn).map_err(ServiceError::from)?;
+
+        return Ok(tonic::Response::new(userservice::BroadcastLeaderboard {
+            broadcast: Some(broadcast.into()),
+            total,
+            entries: rows.into_iter().map(|row| row.into()).collect(),
+        }));
+    }
 }
 
 #[tokio::main]
@@ -673,32 +1649,66 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     debug!("Debug mode activated!");
 
     info!("Loading settings...");
-    let _ = Settings::new()?;
+    let settings = Settings::new()?;
 
     let pool = connect_to_database();
 
-    let youtube_address = env::var("YTS_GRPC_ADDRESS").expect("YTS_GRPC_ADDRESS must be set");
-    let userservice_address = env::var("US_GRPC_ADDRESS");
-    let userservice_address: SocketAddr = if userservice_address.is_err() {
-        "0.0.0.0:50051".parse()?
-    } else {
-        userservice_address.unwrap().parse()?
+    let youtube_address = env::var("YTS_GRPC_ADDRESS").ok().filter(|address| !address.is_empty());
+    let userservice_address: SocketAddr = match env::var("US_GRPC_ADDRESS") {
+        Ok(address) => address.parse()?,
+        Err(_) => "0.0.0.0:50051".parse()?,
     };
 
-    let mut youtube_client = YouTubeServiceClient::connect(youtube_address).await?;
-    info!("Connected to youtubeservice! Time to go on a hunt!");
+    let event_bus = EventBus::new();
+    let cache = Cache::new();
+    if settings.cache_notifications {
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        tokio::spawn(cache.clone().listen_for_changes(database_url));
+    }
+    if settings.deleted_user_retention_days > 0 {
+        tokio::spawn(retention::purge_deleted_users(pool.clone(), settings.deleted_user_retention_days));
+    }
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter.set_serving::<UserServiceServer<UserServer>>().await;
+
+    // The API doesn't depend on the youtubeservice, so ingestion runs in the background, if at all
+    let ingestion = match youtube_address {
+        Some(youtube_address) if settings.ingestion_enabled => {
+            info!("Starting message fetching");
+            let ingestion = Ingestion::new();
+            tokio::spawn(ingestion::supervise(
+                youtube_address,
+                pool.clone(),
+                event_bus.clone(),
+                cache.clone(),
+                ingestion.clone(),
+                health_reporter,
+            ));
+            ingestion
+        }
+        Some(_) => {
+            info!("Message fetching is disabled in the settings, users won't be tracked");
+            Ingestion::disabled()
+        }
+        None => {
+            info!("YTS_GRPC_ADDRESS is not set, users won't be tracked");
+            Ingestion::disabled()
+        }
+    };
 
     let service = UserServer {
-        database_pool: pool.clone()
+        database_pool: pool,
+        event_bus,
+        cache,
+        ingestion,
     };
 
-    info!("Starting message fetching and userservice");
-    let (_, _) = tokio::join!(
-        fetch_users_from_messages(&mut youtube_client, &pool),
-        tonic::transport::Server::builder()
-            .add_service(UserServiceServer::new(service))
-            .serve(userservice_address)
-    );
+    info!("Starting userservice");
+    tonic::transport::Server::builder()
+        .add_service(health_service)
+        .add_service(UserServiceServer::new(service))
+        .serve(userservice_address)
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,352 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Int4, Nullable, Text};
+use prost_types::Duration;
+
+use crate::leaderboard::LeaderboardRow;
+use crate::models::{naive_to_timestamp, Broadcast, InsertBroadcast, InsertSession, Session};
+use crate::paging::Page;
+use crate::schema::{bpp_broadcasts, bpp_sessions, bpp_users};
+use crate::userservice::{BppBroadcast, BppSession};
+use crate::{userservice, youtubeservice};
+
+/// Whether a broadcast is running and what kind of broadcast it is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BroadcastState {
+    /// No broadcast is running, the chat may still be open for members or between streams
+    Offline,
+    Live,
+    /// A pre-recorded video is premiering
+    Premiere,
+}
+
+impl BroadcastState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastState::Offline => "offline",
+            BroadcastState::Live => "live",
+            BroadcastState::Premiere => "premiere",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<BroadcastState> {
+        match state {
+            "offline" => Some(BroadcastState::Offline),
+            "live" => Some(BroadcastState::Live),
+            "premiere" => Some(BroadcastState::Premiere),
+            _ => None,
+        }
+    }
+
+    /// Translates the state reported by the youtubeservice, which may not know the state
+    pub fn from_youtube(state: youtubeservice::BroadcastState) -> Option<BroadcastState> {
+        match state {
+            youtubeservice::BroadcastState::Unknown => None,
+            youtubeservice::BroadcastState::Offline => Some(BroadcastState::Offline),
+            youtubeservice::BroadcastState::Live => Some(BroadcastState::Live),
+            youtubeservice::BroadcastState::Premiere => Some(BroadcastState::Premiere),
+        }
+    }
+}
+
+impl From<BroadcastState> for userservice::BroadcastState {
+    fn from(state: BroadcastState) -> userservice::BroadcastState {
+        match state {
+            BroadcastState::Offline => userservice::BroadcastState::Offline,
+            BroadcastState::Live => userservice::BroadcastState::Live,
+            BroadcastState::Premiere => userservice::BroadcastState::Premiere,
+        }
+    }
+}
+
+impl From<userservice::BroadcastState> for BroadcastState {
+    fn from(state: userservice::BroadcastState) -> BroadcastState {
+        match state {
+            userservice::BroadcastState::Offline => BroadcastState::Offline,
+            userservice::BroadcastState::Live => BroadcastState::Live,
+            userservice::BroadcastState::Premiere => BroadcastState::Premiere,
+        }
+    }
+}
+
+impl Broadcast {
+    pub fn get_state(&self) -> BroadcastState {
+        BroadcastState::parse(&self.state).unwrap_or(BroadcastState::Live)
+    }
+}
+
+/// Returns the state we are in while the given broadcast is running
+pub fn current_state(running: Option<&Broadcast>) -> BroadcastState {
+    running.map(Broadcast::get_state).unwrap_or(BroadcastState::Offline)
+}
+
+/// Whether moving to a broadcast state would leave the running broadcast as it is, see [`set_broadcast_state`]
+pub fn is_in_state(running: Option<&Broadcast>, state: BroadcastState, video_id: &str) -> bool {
+    match running {
+        Some(broadcast) => state == broadcast.get_state() && (video_id.is_empty() || broadcast.video_id == video_id),
+        None => state == BroadcastState::Offline,
+    }
+}
+
+/// How much a user watched, summed over their sessions
+#[derive(QueryableByName)]
+pub struct WatchTime {
+    #[sql_type = "BigInt"]
+    pub watched_seconds: i64,
+    #[sql_type = "BigInt"]
+    pub message_count: i64,
+    #[sql_type = "BigInt"]
+    pub session_count: i64,
+}
+
+/// Loads the broadcast which is currently running, if there is one
+pub fn running_broadcast(conn: &PgConnection) -> QueryResult<Option<Broadcast>> {
+    bpp_broadcasts::table
+        .filter(bpp_broadcasts::ended_at.is_null())
+        .first(conn)
+        .optional()
+}
+
+pub fn get_broadcast(broadcast_id: i32, conn: &PgConnection) -> QueryResult<Option<Broadcast>> {
+    bpp_broadcasts::table.find(broadcast_id).first(conn).optional()
+}
+
+/// Starts a new live broadcast, returns nothing if another broadcast is still running
+pub fn start_broadcast(
+    title: String,
+    video_id: String,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> QueryResult<Option<Broadcast>> {
+    let broadcast = InsertBroadcast {
+        title,
+        video_id,
+        started_at: now,
+        state: BroadcastState::Live.as_str().to_string(),
+    };
+    // The unique index on running broadcasts makes the insert a no-op while one is running
+    diesel::insert_into(bpp_broadcasts::table)
+        .values(&broadcast)
+        .on_conflict_do_nothing()
+        .get_result(conn)
+        .optional()
+}
+
+/// Ends the running broadcast, returns nothing if no broadcast is running
+pub fn end_broadcast(now: NaiveDateTime, conn: &PgConnection) -> QueryResult<Option<Broadcast>> {
+    diesel::update(bpp_broadcasts::table.filter(bpp_broadcasts::ended_at.is_null()))
+        .set(bpp_broadcasts::ended_at.eq(now))
+        .get_result(conn)
+        .optional()
+}
+
+/// Moves to another broadcast state and returns the broadcast which is running afterwards.
+///
+/// Going offline ends the running broadcast. Going live or into a premiere changes the state of the running broadcast
+/// if it shows the same video, an empty video id stands for the running broadcast. Otherwise the running broadcast is
+/// ended and a new one is started.
+pub fn set_broadcast_state(
+    state: BroadcastState,
+    title: &str,
+    video_id: &str,
+    now: NaiveDateTime,
+    conn: &PgConnection,
+) -> QueryResult<Option<Broadcast>> {
+    conn.transaction(|| {
+        // Lock the running broadcast, so the chat and administrators can't start two broadcasts at once
+        let running = bpp_broadcasts::table
+            .filter(bpp_broadcasts::ended_at.is_null())
+            .for_update()
+            .first::<Broadcast>(conn)
+            .optional()?;
+
+        if let Some(broadcast) = &running {
+            if state != BroadcastState::Offline && (video_id.is_empty() || broadcast.video_id == video_id) {
+                if broadcast.get_state() == state {
+                    return Ok(running);
+                }
+                return diesel::update(broadcast)
+                    .set(bpp_broadcasts::state.eq(state.as_str()))
+                    .get_result(conn)
+                    .map(Some);
+            }
+            end_broadcast(now, conn)?;
+        }
+        if state == BroadcastState::Offline {
+            return Ok(None);
+        }
+
+        let broadcast = InsertBroadcast {
+            title: title.to_string(),
+            video_id: video_id.to_string(),
+            started_at: now,
+            state: state.as_str().to_string(),
+        };
+        diesel::insert_into(bpp_broadcasts::table)
+            .values(&broadcast)
+            .get_result(conn)
+            .map(Some)
+    })
+}
+
+/// Loads one page of broadcasts, newest first, together with the total number of broadcasts
+pub fn get_broadcasts(page: &Page, conn: &PgConnection) -> QueryResult<(i64, Vec<Broadcast>)> {
+    let total = bpp_broadcasts::table.count().get_result(conn)?;
+    let broadcasts = bpp_broadcasts::table
+        .order(bpp_broadcasts::broadcast_id.desc())
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<Broadcast>(conn)?;
+    Ok((total, broadcasts))
+}
+
+/// Adds a message of a user to their watch session.
+///
+/// A message continues the latest session of the user if it was sent within `active_time` of the previous message
+/// and during the same broadcast, which adds the time in between to the session. Otherwise it starts a new session.
+/// Sessions are tracked in every broadcast state, even if watching doesn't earn hours in it.
+pub fn record_message(
+    channel_id: &str,
+    broadcast_id: Option<i32>,
+    now: NaiveDateTime,
+    active_time: chrono::Duration,
+    conn: &PgConnection,
+) -> QueryResult<Session> {
+    let latest = bpp_sessions::table
+        .filter(bpp_sessions::channel_id.eq(channel_id))
+        .order(bpp_sessions::ended_at.desc())
+        .first::<Session>(conn)
+        .optional()?;
+
+    match latest {
+        Some(session) if session.broadcast_id == broadcast_id && session.ended_at + active_time > now => {
+            let watched = (now - session.ended_at).num_seconds();
+            diesel::update(&session)
+                .set((
+                    bpp_sessions::ended_at.eq(now),
+                    bpp_sessions::message_count.eq(bpp_sessions::message_count + 1),
+                    bpp_sessions::duration_seconds.eq(bpp_sessions::duration_seconds + watched),
+                ))
+                .get_result(conn)
+        }
+        _ => {
+            let session = InsertSession {
+                channel_id: channel_id.to_string(),
+                broadcast_id,
+                started_at: now,
+                ended_at: now,
+                message_count: 1,
+                duration_seconds: 0,
+            };
+            diesel::insert_into(bpp_sessions::table).values(&session).get_result(conn)
+        }
+    }
+}
+
+/// Loads one page of the sessions of a user, newest first, together with the total number of matching sessions.
+///
+/// Without a broadcast id the sessions of all broadcasts are loaded.
+pub fn get_sessions(
+    channel_id: &str,
+    broadcast_id: Option<i32>,
+    page: &Page,
+    conn: &PgConnection,
+) -> QueryResult<(i64, Vec<Session>)> {
+    let mut count_query = bpp_sessions::table
+        .filter(bpp_sessions::channel_id.eq(channel_id.to_string()))
+        .into_boxed();
+    let mut query = bpp_sessions::table
+        .filter(bpp_sessions::channel_id.eq(channel_id.to_string()))
+        .into_boxed();
+    if let Some(broadcast_id) = broadcast_id {
+        count_query = count_query.filter(bpp_sessions::broadcast_id.eq(broadcast_id));
+        query = query.filter(bpp_sessions::broadcast_id.eq(broadcast_id));
+    }
+
+    let total = count_query.count().get_result(conn)?;
+    let sessions = query
+        .order((bpp_sessions::started_at.desc(), bpp_sessions::session_id.desc()))
+        .limit(page.limit)
+        .offset(page.offset)
+        .load::<Session>(conn)?;
+    Ok((total, sessions))
+}
+
+/// Sums up the sessions of a user, either during one broadcast or, without a broadcast id, overall
+pub fn watch_time(channel_id: &str, broadcast_id: Option<i32>, conn: &PgConnection) -> QueryResult<WatchTime> {
+    diesel::sql_query(
+        "SELECT COALESCE(SUM(duration_seconds), 0)::BIGINT AS watched_seconds,
+            COALESCE(SUM(message_count), 0)::BIGINT AS message_count,
+            COUNT(*) AS session_count
+        FROM bpp_sessions
+        WHERE channel_id = $1 AND ($2 IS NULL OR broadcast_id = $2)",
+    )
+    .bind::<Text, _>(channel_id)
+    .bind::<Nullable<Int4>, _>(broadcast_id)
+    .get_result(conn)
+}
+
+/// Loads the users who watched the most of a broadcast together with the number of users who watched it, leaving out
+/// deleted users
+pub fn top_watchers(broadcast_id: i32, limit: i64, conn: &PgConnection) -> QueryResult<(i64, Vec<LeaderboardRow>)> {
+    let watchers = bpp_sessions::table
+        .filter(bpp_sessions::broadcast_id.eq(broadcast_id))
+        .select(bpp_sessions::channel_id);
+    let total = bpp_users::table
+        .filter(bpp_users::channel_id.eq_any(watchers))
+        .filter(bpp_users::deleted_at.is_null())
+        .count()
+        .get_result(conn)?;
+    let rows = diesel::sql_query(
+        "WITH watched AS (
+            SELECT channel_id, SUM(duration_seconds)::BIGINT AS value
+            FROM bpp_sessions
+            WHERE broadcast_id = $1
+            GROUP BY channel_id
+        )
+        SELECT RANK() OVER (ORDER BY watched.value DESC) AS position,
+            bpp_users.channel_id,
+            bpp_users.display_name,
+            watched.value
+        FROM watched
+        INNER JOIN bpp_users ON bpp_users.channel_id = watched.channel_id
+        WHERE bpp_users.deleted_at IS NULL
+        ORDER BY watched.value DESC, bpp_users.channel_id ASC
+        LIMIT $2",
+    )
+    .bind::<Int4, _>(broadcast_id)
+    .bind::<BigInt, _>(limit)
+    .load(conn)?;
+    Ok((total, rows))
+}
+
+impl From<Broadcast> for BppBroadcast {
+    fn from(broadcast: Broadcast) -> BppBroadcast {
+        let state = userservice::BroadcastState::from(broadcast.get_state());
+        BppBroadcast {
+            broadcast_id: broadcast.broadcast_id,
+            title: broadcast.title,
+            video_id: broadcast.video_id,
+            started_at: Some(naive_to_timestamp(&broadcast.started_at)),
+            ended_at: broadcast.ended_at.as_ref().map(naive_to_timestamp),
+            state: state as i32,
+        }
+    }
+}
+
+impl From<Session> for BppSession {
+    fn from(session: Session) -> BppSession {
+        BppSession {
+            session_id: session.session_id,
+            channel_id: session.channel_id,
+            broadcast_id: session.broadcast_id.unwrap_or(0),
+            started_at: Some(naive_to_timestamp(&session.started_at)),
+            ended_at: Some(naive_to_timestamp(&session.ended_at)),
+            message_count: session.message_count,
+            duration: Some(Duration {
+                seconds: session.duration_seconds,
+                nanos: 0,
+            }),
+        }
+    }
+}
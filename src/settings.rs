@@ -6,17 +6,62 @@ use config::{ConfigError, Config};
 use config::File as ConfigFile;
 use log::debug;
 
+use crate::sessions::BroadcastState;
+
+/// The longest retention of deleted users that can be configured, about a hundred years
+pub const MAX_RETENTION_DAYS: i32 = 36_500;
+
+/// What watching earns in one broadcast state
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Accrual {
+    pub hours: bool,
+    pub money: bool,
+}
+
+impl Default for Accrual {
+    fn default() -> Accrual {
+        Accrual {
+            hours: true,
+            money: true,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub default_payout: i32,
-    pub active_time: i32
+    pub active_time: i32,
+    /// Percentage of a transfer the sender pays on top as a fee, from 0 to 100. 0 disables the fee
+    pub transfer_fee_percent: i32,
+    /// Listen for Postgres notifications to invalidate the cache when other processes change groups, ranks or
+    /// permissions
+    pub cache_notifications: bool,
+    /// Track the users of the youtubeservice messages, without it the service only serves the API
+    pub ingestion_enabled: bool,
+    /// Days deleted users are kept so they can be restored, before they are deleted for good. 0 keeps them forever,
+    /// at most [`MAX_RETENTION_DAYS`] are allowed
+    pub deleted_user_retention_days: i32,
+    // Tables have to come after all plain values in TOML
+    pub live_accrual: Accrual,
+    pub premiere_accrual: Accrual,
+    /// Applies while no broadcast is running
+    pub offline_accrual: Accrual
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
             default_payout: 1,
-            active_time: 5 * 60
+            active_time: 5 * 60,
+            transfer_fee_percent: 0,
+            cache_notifications: false,
+            ingestion_enabled: true,
+            deleted_user_retention_days: 30,
+            live_accrual: Accrual::default(),
+            premiere_accrual: Accrual::default(),
+            offline_accrual: Accrual::default()
         }
     }
 }
@@ -39,7 +84,31 @@ impl Settings {
         }
 
         s.merge(ConfigFile::with_name("config/userservice"))?;
-        s.try_into()
+        let settings: Settings = s.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Rejects values which can be parsed but make no sense
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(0..=100).contains(&self.transfer_fee_percent) {
+            return Err(ConfigError::Message("transfer_fee_percent must be between 0 and 100".to_string()));
+        }
+        if !(0..=MAX_RETENTION_DAYS).contains(&self.deleted_user_retention_days) {
+            return Err(ConfigError::Message(format!(
+                "deleted_user_retention_days must be between 0 and {}",
+                MAX_RETENTION_DAYS
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn accrual(&self, state: BroadcastState) -> Accrual {
+        match state {
+            BroadcastState::Live => self.live_accrual,
+            BroadcastState::Premiere => self.premiere_accrual,
+            BroadcastState::Offline => self.offline_accrual,
+        }
     }
 
     /// Saves the configuration to the file